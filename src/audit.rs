@@ -0,0 +1,141 @@
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+use serde_json::{Value as JsonValue, json};
+
+use crate::config::LogFileConfig;
+use crate::filter::FilterFlags;
+use crate::log_file::LogFile;
+use crate::server::Server;
+use crate::server_info::{ServerFlags, ServerInfo};
+
+/// Append-only JSON lines record of server registrations.
+pub struct AuditLog {
+    file: LogFile,
+}
+
+impl AuditLog {
+    pub fn open(cfg: &LogFileConfig) -> io::Result<Self> {
+        Ok(Self {
+            file: LogFile::open(cfg)?,
+        })
+    }
+
+    pub fn add(&mut self, addr: SocketAddrV4, info: &ServerInfo<&str>) {
+        self.write("add", addr, info_json(info));
+    }
+
+    pub fn update(&mut self, addr: SocketAddrV4, info: &ServerInfo<&str>) {
+        self.write("update", addr, info_json(info));
+    }
+
+    pub fn expire(&mut self, addr: SocketAddrV4, server: &Server) {
+        self.write("expire", addr, server_json(server));
+    }
+
+    pub fn evict(&mut self, addr: SocketAddrV4, server: &Server) {
+        self.write("evict", addr, server_json(server));
+    }
+
+    /// Records an unauthenticated remove request, `server` is the registered
+    /// server which is kept.
+    pub fn reject_remove(&mut self, addr: SocketAddrV4, server: Option<&Server>) {
+        self.write(
+            "remove_rejected",
+            addr,
+            server.map_or(JsonValue::Null, server_json),
+        );
+    }
+
+    fn write(&mut self, event: &str, addr: SocketAddrV4, server: JsonValue) {
+        let record = json!({
+            "timestamp": timestamp(),
+            "event": event,
+            "addr": addr.to_string(),
+            "server": server,
+        });
+        if let Err(e) = self.file.write_line(&record.to_string()) {
+            error!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn info_json(info: &ServerInfo<&str>) -> JsonValue {
+    json!({
+        "gamedir": info.gamedir,
+        "map": info.map,
+        "version": info.version,
+        "product": info.product,
+        "type": info.server_type.to_string(),
+        "os": info.os.to_string(),
+        "region": info.region as u8,
+        "protocol": info.protocol,
+        "players": info.players,
+        "max": info.max,
+        "bots": info.flags.contains(ServerFlags::BOTS),
+        "password": info.flags.contains(ServerFlags::PASSWORD),
+        "secure": info.flags.contains(ServerFlags::SECURE),
+        "lan": info.flags.contains(ServerFlags::LAN),
+        "nat": info.flags.contains(ServerFlags::NAT),
+    })
+}
+
+/// Same fields as `info_json` for servers already in the table.
+fn server_json(server: &Server) -> JsonValue {
+    json!({
+        "gamedir": &*server.gamedir,
+        "map": &*server.map,
+        "version": &*server.version,
+        "product": &*server.product,
+        "type": server.server_type.to_string(),
+        "os": server.os.to_string(),
+        "region": server.region as u8,
+        "protocol": server.protocol,
+        "players": server.players,
+        "max": server.max,
+        "bots": server.flags.contains(FilterFlags::BOTS),
+        "password": server.flags.contains(FilterFlags::PASSWORD),
+        "secure": server.flags.contains(FilterFlags::SECURE),
+        "lan": server.flags.contains(FilterFlags::LAN),
+        "nat": server.flags.contains(FilterFlags::NAT),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_fields() {
+        let (_, info, _) = ServerInfo::<&str>::from_bytes(
+            b"\\gamedir\\cstrike\\map\\de_dust\\players\\3\\os\\l\\lan\\1",
+        )
+        .unwrap();
+        let value = info_json(&info);
+        assert_eq!(value["gamedir"], "cstrike");
+        assert_eq!(value["map"], "de_dust");
+        assert_eq!(value["players"], 3);
+        assert_eq!(value["os"], "Linux");
+        assert_eq!(value["region"], 255);
+        assert_eq!(value["lan"], true);
+        assert_eq!(value["secure"], false);
+    }
+
+    #[test]
+    fn server_fields() {
+        let (_, info, _) = ServerInfo::<&str>::from_bytes(
+            b"\\gamedir\\cstrike\\product\\cstrike\\protocol\\48\\type\\d\\os\\w\\nat\\1",
+        )
+        .unwrap();
+        let server = Server::new(&info, &mut Default::default());
+        assert_eq!(server_json(&server), info_json(&info));
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fastrand::Rng;
+use log::{debug, info};
+use thiserror::Error;
+
+use crate::cli::BenchCli;
+use crate::master_server::{MAX_PACKET_SIZE, MAX_SERVERS_PER_PACKET};
+use crate::query::{self, decode_response, encode_request, is_timeout, resolve};
+use crate::register;
+use crate::server_info::{Os, Region, ServerFlags, ServerInfo, ServerType};
+
+const GAMEDIRS: &[&str] = &["valve", "cstrike", "dod", "tfc", "gearbox"];
+const MAPS: &[&str] = &["crossfire", "de_dust2", "cs_office", "dod_anzio", "2fort"];
+const REGIONS: &[Region] = &[
+    Region::USEastCoast,
+    Region::USWestCoast,
+    Region::SouthAmerica,
+    Region::Europe,
+    Region::Asia,
+    Region::Australia,
+    Region::MiddleEast,
+    Region::Africa,
+    Region::RestOfTheWorld,
+];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Query(#[from] query::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Results collected by a worker thread.
+#[derive(Default)]
+struct Report {
+    sent: usize,
+    lost: usize,
+    results: usize,
+    latency: Vec<Duration>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.sent += other.sent;
+        self.lost += other.lost;
+        self.results += other.results;
+        self.latency.extend(other.latency);
+    }
+
+    fn print(&mut self, name: &str, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let loss = match self.sent {
+            0 => 0.0,
+            n => self.lost as f64 * 100.0 / n as f64,
+        };
+        println!(
+            "{}: {} in {:.2}s ({:.1}/s), lost {} ({:.2}%)",
+            name,
+            self.sent,
+            secs,
+            (self.sent - self.lost) as f64 / secs,
+            self.lost,
+            loss
+        );
+        if self.results != 0 {
+            println!(
+                "  results: {} ({:.1}/s)",
+                self.results,
+                self.results as f64 / secs
+            );
+        }
+        self.latency.sort_unstable();
+        println!(
+            "  latency: p50 {} p90 {} p99 {} max {}",
+            Ms(percentile(&self.latency, 50)),
+            Ms(percentile(&self.latency, 90)),
+            Ms(percentile(&self.latency, 99)),
+            Ms(self.latency.last().copied()),
+        );
+    }
+}
+
+struct Ms(Option<Duration>);
+
+impl fmt::Display for Ms {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(d) => write!(fmt, "{:.3}ms", d.as_secs_f64() * 1000.0),
+            None => write!(fmt, "-"),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted `values`.
+fn percentile(values: &[Duration], p: usize) -> Option<Duration> {
+    if values.is_empty() {
+        return None;
+    }
+    let rank = (values.len() * p).div_ceil(100).max(1);
+    values.get(rank - 1).copied()
+}
+
+pub fn run(cli: BenchCli) -> Result<(), Error> {
+    let addr = resolve(&cli.addr)?;
+    let timeout = Duration::from_millis(cli.timeout);
+    let threads = cli.clients.max(1);
+    info!(
+        "Benchmark {} with {} servers and {} clients",
+        addr, cli.servers, cli.clients
+    );
+
+    // registrations and queries run at the same time to contend for the server table
+    let start = Instant::now();
+    let registers = (0..threads)
+        .map(|i| {
+            let count = cli.servers / threads + usize::from(i < cli.servers % threads);
+            thread::spawn(move || register_servers(addr, count, timeout))
+        })
+        .collect::<Vec<_>>();
+    let duration = Duration::from_secs(cli.duration);
+    let queries = (0..cli.clients)
+        .map(|_| thread::spawn(move || query_servers(addr, duration, timeout)))
+        .collect::<Vec<_>>();
+
+    let mut report = Report::default();
+    for handle in registers {
+        report.merge(handle.join().expect("register thread panicked")?);
+    }
+    report.print("Registrations", start.elapsed());
+
+    if cli.clients == 0 {
+        return Ok(());
+    }
+
+    let mut report = Report::default();
+    for handle in queries {
+        report.merge(handle.join().expect("query thread panicked")?);
+    }
+    report.print("Queries", start.elapsed());
+
+    Ok(())
+}
+
+fn random_info(rng: &mut Rng) -> ServerInfo<&'static str> {
+    let max = rng.u8(1..=32);
+    let mut flags = ServerFlags::empty();
+    flags.set(ServerFlags::BOTS, rng.bool());
+    flags.set(ServerFlags::PASSWORD, rng.u8(..10) == 0);
+    flags.set(ServerFlags::SECURE, rng.bool());
+
+    ServerInfo {
+        gamedir: GAMEDIRS[rng.usize(..GAMEDIRS.len())],
+        map: MAPS[rng.usize(..MAPS.len())],
+        version: "1.1.2.7",
+        product: "valve",
+        server_type: match rng.bool() {
+            true => ServerType::Dedicated,
+            false => ServerType::Local,
+        },
+        os: match rng.bool() {
+            true => Os::Linux,
+            false => Os::Windows,
+        },
+        region: REGIONS[rng.usize(..REGIONS.len())],
+        protocol: 48,
+        players: rng.u8(..=max),
+        max,
+        flags,
+    }
+}
+
+fn random_filter(rng: &mut Rng) -> String {
+    let gamedir = GAMEDIRS[rng.usize(..GAMEDIRS.len())];
+    let map = MAPS[rng.usize(..MAPS.len())];
+    match rng.u8(..5) {
+        0 => String::new(),
+        1 => format!("\\gamedir\\{}", gamedir),
+        2 => format!("\\gamedir\\{}\\map\\{}", gamedir, map),
+        3 => format!("\\gamedir\\{}\\empty\\1\\full\\0", gamedir),
+        _ => format!("\\secure\\1\\password\\0\\linux\\{}", rng.u8(..2)),
+    }
+}
+
+/// Registers `count` servers, each from its own socket.
+fn register_servers(addr: SocketAddr, count: usize, timeout: Duration) -> Result<Report, Error> {
+    let mut rng = Rng::new();
+    let mut report = Report::default();
+    for _ in 0..count {
+        let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        sock.connect(addr)?;
+        sock.set_read_timeout(Some(timeout))?;
+
+        let info = random_info(&mut rng);
+        let start = Instant::now();
+        report.sent += 1;
+        match register::heartbeat(&sock, &info) {
+            Ok(()) => report.latency.push(start.elapsed()),
+            Err(register::Error::Timeout) => report.lost += 1,
+            Err(register::Error::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                debug!("{}", e);
+                report.lost += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Sends random queries until `duration` elapses, waiting for each response.
+///
+/// Every datagram of this master ends with `0.0.0.0:0`, the last datagram of a
+/// response has less than `MAX_SERVERS_PER_PACKET` servers.
+fn query_servers(addr: SocketAddr, duration: Duration, timeout: Duration) -> Result<Report, Error> {
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    sock.connect(addr)?;
+    sock.set_read_timeout(Some(timeout))?;
+
+    let seed = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut rng = Rng::new();
+    let mut report = Report::default();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        let region = REGIONS[rng.usize(..REGIONS.len())];
+        let filter = random_filter(&mut rng);
+        let start = Instant::now();
+        sock.send(&encode_request(region, seed, &filter))?;
+        report.sent += 1;
+
+        loop {
+            let n = match sock.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if is_timeout(&e) => {
+                    report.lost += 1;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let (list, terminated) = decode_response(&buf[..n])?;
+            report.results += list.len();
+            if terminated && list.len() < MAX_SERVERS_PER_PACKET {
+                report.latency.push(start.elapsed());
+                break;
+            }
+        }
+    }
+
+    // drop late datagrams of timed out queries
+    sock.set_read_timeout(Some(Duration::from_millis(1)))?;
+    while sock.recv(&mut buf).is_ok() {}
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|&i| Duration::from_millis(i)).collect()
+    }
+
+    #[test]
+    fn percentiles() {
+        let values = ms(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(percentile(&values, 50), Some(Duration::from_millis(5)));
+        assert_eq!(percentile(&values, 90), Some(Duration::from_millis(9)));
+        assert_eq!(percentile(&values, 99), Some(Duration::from_millis(10)));
+        assert_eq!(percentile(&values, 0), Some(Duration::from_millis(1)));
+        assert_eq!(percentile(&[], 50), None);
+    }
+}
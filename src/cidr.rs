@@ -0,0 +1,137 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, de::Error as _};
+use thiserror::Error;
+
+#[derive(Copy, Clone, Error, Debug, PartialEq, Eq)]
+#[error("Invalid CIDR")]
+pub struct Error;
+
+/// IPv4 network in CIDR notation, a plain address is parsed as `/32`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ipv4Cidr {
+    addr: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Ipv4Cidr {
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Result<Self, Error> {
+        if prefix > 32 {
+            return Err(Error);
+        }
+        let addr = Ipv4Addr::from(u32::from(addr) & mask(prefix));
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & mask(self.prefix) == u32::from(self.addr)
+    }
+}
+
+/// Private, loopback, link-local and shared (carrier-grade NAT) networks.
+const PRIVATE_RANGES: [([u8; 4], u8); 6] = [
+    ([10, 0, 0, 0], 8),
+    ([172, 16, 0, 0], 12),
+    ([192, 168, 0, 0], 16),
+    ([127, 0, 0, 0], 8),
+    ([169, 254, 0, 0], 16),
+    ([100, 64, 0, 0], 10),
+];
+
+/// Returns the private network containing `ip`, `None` for public addresses.
+pub fn private_range(ip: Ipv4Addr) -> Option<Ipv4Cidr> {
+    PRIVATE_RANGES
+        .iter()
+        .map(|&(addr, prefix)| Ipv4Cidr::new(addr.into(), prefix).unwrap())
+        .find(|i| i.contains(ip))
+}
+
+/// Returns `true` if both addresses are in the same private network or behind
+/// the same public address.
+pub fn same_network(a: Ipv4Addr, b: Ipv4Addr) -> bool {
+    a == b || private_range(a).is_some_and(|i| i.contains(b))
+}
+
+fn mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix.parse().map_err(|_| Error)?),
+            None => (s, 32),
+        };
+        Self::new(addr.parse().map_err(|_| Error)?, prefix)
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Cidr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| D::Error::custom(format!("Invalid CIDR: \"{}\"", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Ipv4Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(cidr("10.1.2.3").to_string(), "10.1.2.3/32");
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("10.1.2.3/0").to_string(), "0.0.0.0/0");
+        assert_eq!("10.1.2.3/33".parse::<Ipv4Cidr>(), Err(Error));
+        assert_eq!("10.1.2/8".parse::<Ipv4Cidr>(), Err(Error));
+        assert_eq!("10.1.2.3/".parse::<Ipv4Cidr>(), Err(Error));
+    }
+
+    #[test]
+    fn contains() {
+        assert!(cidr("192.168.0.0/16").contains(Ipv4Addr::new(192, 168, 10, 1)));
+        assert!(!cidr("192.168.0.0/16").contains(Ipv4Addr::new(192, 169, 0, 1)));
+        assert!(cidr("0.0.0.0/0").contains(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(cidr("1.2.3.4").contains(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(!cidr("1.2.3.4").contains(Ipv4Addr::new(1, 2, 3, 5)));
+    }
+
+    #[test]
+    fn private() {
+        let range = |ip: [u8; 4]| private_range(ip.into()).map(|i| i.to_string());
+        assert_eq!(range([10, 1, 2, 3]).as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(range([172, 31, 0, 1]).as_deref(), Some("172.16.0.0/12"));
+        assert_eq!(range([127, 0, 0, 1]).as_deref(), Some("127.0.0.0/8"));
+        assert_eq!(range([100, 100, 0, 1]).as_deref(), Some("100.64.0.0/10"));
+        assert_eq!(range([172, 32, 0, 1]), None);
+        assert_eq!(range([8, 8, 8, 8]), None);
+
+        let ip = |ip: [u8; 4]| Ipv4Addr::from(ip);
+        assert!(same_network(ip([192, 168, 1, 2]), ip([192, 168, 7, 1])));
+        assert!(same_network(ip([1, 2, 3, 4]), ip([1, 2, 3, 4])));
+        assert!(!same_network(ip([1, 2, 3, 4]), ip([1, 2, 3, 5])));
+        assert!(!same_network(ip([192, 168, 1, 2]), ip([10, 0, 0, 1])));
+    }
+}
@@ -1,15 +1,24 @@
 use lexopt::prelude::*;
 use log::LevelFilter;
-use std::net::IpAddr;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::process;
 use thiserror::Error;
 
 use crate::config;
+use crate::filter::Filter;
+use crate::server_info::{Region, ServerInfo};
 
 const BIN_NAME: &str = env!("CARGO_BIN_NAME");
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 const HELP: &str = "\
+COMMANDS:
+  query                 Query server list from a master, see `query --help`
+  register              Simulate game server heartbeats, see `register --help`
+  bench                 Benchmark a master with simulated load, see `bench --help`
+
 OPTIONS:
   -h, --help            Print usage help
   -v, --version         Print program version
@@ -18,6 +27,50 @@ OPTIONS:
   -p, --port PORT       Set the listen port
   -c, --config PATH     Set the config path
 ";
+const QUERY_HELP: &str = "\
+ARGS:
+  ADDR                  Master address, default port is 27010
+
+OPTIONS:
+  -h, --help            Print usage help
+  -r, --region REGION   Region code, 0-7 or 255 (default)
+  -f, --filter FILTER   Filter string, e.g. \"\\gamedir\\cstrike\"
+  -t, --timeout MS      Read timeout in milliseconds (default 3000)
+  -j, --json            Print the server list as JSON array
+";
+const REGISTER_HELP: &str = "\
+ARGS:
+  ADDR                  Master address, default port is 27010
+
+OPTIONS:
+  -h, --help            Print usage help
+  -b, --bind ADDR       Local address to send from (default 0.0.0.0:0)
+  -f, --file PATH       Read heartbeat key-values from file (\\key\\value...)
+  -n, --interval SECS   Repeat heartbeat every SECS seconds
+  -r, --remove          Send remove request instead of heartbeat (ignored by phantasma)
+  -t, --timeout MS      Read timeout in milliseconds (default 3000)
+
+HEARTBEAT OPTIONS (override values from file):
+  --protocol N          --gamedir DIR         --map MAP
+  --version VERSION     --product PRODUCT     --region REGION
+  --players N           --max N               --os l|w|m
+  --type d|l|p          --bots                --password
+  --secure              --lan
+";
+const BENCH_HELP: &str = "\
+ARGS:
+  ADDR                  Master address, default port is 27010
+
+OPTIONS:
+  -h, --help            Print usage help
+  -s, --servers N       Number of servers to register (default 1000)
+  -c, --clients N       Number of querying clients (default 4)
+  -d, --duration SECS   Duration of the queries (default 10)
+  -t, --timeout MS      Read timeout in milliseconds (default 3000)
+";
+
+/// Default read timeout in milliseconds for client commands.
+const DEFAULT_TIMEOUT: u64 = 3000;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -25,18 +78,247 @@ pub enum Error {
     InvalidIp(String),
     #[error("Invalid port number \"{0}\"")]
     InvalidPort(String),
+    #[error("Invalid region \"{0}\"")]
+    InvalidRegion(String),
+    #[error("Invalid filter \"{0}\"")]
+    InvalidFilter(String),
+    #[error("Invalid timeout \"{0}\"")]
+    InvalidTimeout(String),
+    #[error("Invalid bind address \"{0}\"")]
+    InvalidBind(String),
+    #[error("Invalid interval \"{0}\"")]
+    InvalidInterval(String),
+    #[error("Invalid number of {0} \"{1}\"")]
+    InvalidNumber(&'static str, String),
+    #[error("Invalid server info: {0}")]
+    InvalidServerInfo(String),
+    #[error("Failed to read \"{0}\": {1}")]
+    ReadFile(String, io::Error),
     #[error(transparent)]
     Options(#[from] lexopt::Error),
 }
 
+#[derive(Debug, Default)]
+pub enum Command {
+    /// Run the master server.
+    #[default]
+    Server,
+    Query(QueryCli),
+    Register(RegisterCli),
+    Bench(BenchCli),
+}
+
 #[derive(Debug, Default)]
 pub struct Cli {
+    pub command: Command,
     pub log_level: Option<LevelFilter>,
     pub listen_ip: Option<IpAddr>,
     pub listen_port: Option<u16>,
     pub config_path: Option<Box<str>>,
 }
 
+#[derive(Debug)]
+pub struct QueryCli {
+    pub addr: Box<str>,
+    pub region: Region,
+    pub filter: Box<str>,
+    pub timeout: u64,
+    pub json: bool,
+}
+
+#[derive(Debug)]
+pub struct RegisterCli {
+    pub addr: Box<str>,
+    pub bind: SocketAddr,
+    pub info: ServerInfo,
+    pub interval: Option<u64>,
+    pub remove: bool,
+    pub timeout: u64,
+}
+
+#[derive(Debug)]
+pub struct BenchCli {
+    pub addr: Box<str>,
+    pub servers: usize,
+    pub clients: usize,
+    pub duration: u64,
+    pub timeout: u64,
+}
+
+fn parse_string(parser: &mut lexopt::Parser, what: &str) -> Result<String, Error> {
+    parser
+        .value()?
+        .into_string()
+        .map_err(|_| Error::Options(format!("Failed to parse {} option", what).into()))
+}
+
+fn parse_query(parser: &mut lexopt::Parser) -> Result<QueryCli, Error> {
+    let mut addr = None;
+    let mut region = Region::RestOfTheWorld;
+    let mut filter = String::new();
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut json = false;
+
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                print!("USAGE: {} query [options] ADDR\n\n{}", BIN_NAME, QUERY_HELP);
+                process::exit(0);
+            }
+            Short('r') | Long("region") => {
+                let s = parse_string(parser, "region")?;
+                region = s.parse().map_err(|_| Error::InvalidRegion(s))?;
+            }
+            Short('f') | Long("filter") => {
+                let s = parse_string(parser, "filter")?;
+                if Filter::from_bytes(s.as_bytes()).is_err() {
+                    return Err(Error::InvalidFilter(s));
+                }
+                filter = s;
+            }
+            Short('t') | Long("timeout") => {
+                let s = parse_string(parser, "timeout")?;
+                timeout = s.parse().map_err(|_| Error::InvalidTimeout(s))?;
+            }
+            Short('j') | Long("json") => json = true,
+            Value(v) if addr.is_none() => {
+                let s = v
+                    .into_string()
+                    .map_err(|_| Error::Options("Failed to parse master address".into()))?;
+                addr = Some(s.into_boxed_str());
+            }
+            _ => return Err(arg.unexpected().into()),
+        }
+    }
+
+    Ok(QueryCli {
+        addr: addr.ok_or_else(|| Error::Options("Missing master address".into()))?,
+        region,
+        filter: filter.into_boxed_str(),
+        timeout,
+        json,
+    })
+}
+
+fn parse_register(parser: &mut lexopt::Parser) -> Result<RegisterCli, Error> {
+    let mut addr = None;
+    let mut bind = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let mut file = Vec::new();
+    let mut fields = Vec::new();
+    let mut interval = None;
+    let mut remove = false;
+    let mut timeout = DEFAULT_TIMEOUT;
+
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                print!(
+                    "USAGE: {} register [options] ADDR\n\n{}",
+                    BIN_NAME, REGISTER_HELP
+                );
+                process::exit(0);
+            }
+            Short('b') | Long("bind") => {
+                let s = parse_string(parser, "bind")?;
+                bind = s.parse().map_err(|_| Error::InvalidBind(s))?;
+            }
+            Short('f') | Long("file") => {
+                let path = parse_string(parser, "file")?;
+                file = fs::read(&path).map_err(|e| Error::ReadFile(path, e))?;
+                file.truncate(file.trim_ascii_end().len());
+            }
+            Short('n') | Long("interval") => {
+                let s = parse_string(parser, "interval")?;
+                interval = Some(s.parse().map_err(|_| Error::InvalidInterval(s))?);
+            }
+            Short('r') | Long("remove") => remove = true,
+            Short('t') | Long("timeout") => {
+                let s = parse_string(parser, "timeout")?;
+                timeout = s.parse().map_err(|_| Error::InvalidTimeout(s))?;
+            }
+            Long(
+                key @ ("protocol" | "gamedir" | "map" | "version" | "product" | "region"
+                | "players" | "max" | "os" | "type"),
+            ) => {
+                let key = key.to_string();
+                let value = parse_string(parser, &key)?;
+                fields.extend_from_slice(format!("\\{}\\{}", key, value).as_bytes());
+            }
+            Long(key @ ("bots" | "password" | "secure" | "lan")) => {
+                fields.extend_from_slice(format!("\\{}\\1", key).as_bytes());
+            }
+            Value(v) if addr.is_none() => {
+                let s = v
+                    .into_string()
+                    .map_err(|_| Error::Options("Failed to parse master address".into()))?;
+                addr = Some(s.into_boxed_str());
+            }
+            _ => return Err(arg.unexpected().into()),
+        }
+    }
+
+    // fields from flags are parsed last to override values from file
+    file.extend_from_slice(&fields);
+    let info = match ServerInfo::from_bytes(&file) {
+        Ok((_, info, [])) => info,
+        Ok(_) => return Err(Error::InvalidServerInfo("unexpected data at end".into())),
+        Err(e) => return Err(Error::InvalidServerInfo(e.to_string())),
+    };
+
+    Ok(RegisterCli {
+        addr: addr.ok_or_else(|| Error::Options("Missing master address".into()))?,
+        bind,
+        info,
+        interval,
+        remove,
+        timeout,
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(
+    parser: &mut lexopt::Parser,
+    what: &'static str,
+) -> Result<T, Error> {
+    let s = parse_string(parser, what)?;
+    s.parse().map_err(|_| Error::InvalidNumber(what, s))
+}
+
+fn parse_bench(parser: &mut lexopt::Parser) -> Result<BenchCli, Error> {
+    let mut addr = None;
+    let mut servers = 1000;
+    let mut clients = 4;
+    let mut duration = 10;
+    let mut timeout = DEFAULT_TIMEOUT;
+
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                print!("USAGE: {} bench [options] ADDR\n\n{}", BIN_NAME, BENCH_HELP);
+                process::exit(0);
+            }
+            Short('s') | Long("servers") => servers = parse_number(parser, "servers")?,
+            Short('c') | Long("clients") => clients = parse_number(parser, "clients")?,
+            Short('d') | Long("duration") => duration = parse_number(parser, "seconds")?,
+            Short('t') | Long("timeout") => timeout = parse_number(parser, "milliseconds")?,
+            Value(v) if addr.is_none() => {
+                let s = v
+                    .into_string()
+                    .map_err(|_| Error::Options("Failed to parse master address".into()))?;
+                addr = Some(s.into_boxed_str());
+            }
+            _ => return Err(arg.unexpected().into()),
+        }
+    }
+
+    Ok(BenchCli {
+        addr: addr.ok_or_else(|| Error::Options("Missing master address".into()))?,
+        servers,
+        clients,
+        duration,
+        timeout,
+    })
+}
+
 pub fn parse() -> Result<Cli, Error> {
     let mut cli = Cli::default();
 
@@ -45,7 +327,7 @@ pub fn parse() -> Result<Cli, Error> {
     while let Some(arg) = parser.next()? {
         match arg {
             Short('h') | Long("help") => {
-                print!("USAGE: {} [options]\n\n{}", BIN_NAME, HELP);
+                print!("USAGE: {} [options] [COMMAND]\n\n{}", BIN_NAME, HELP);
                 process::exit(0);
             }
             Short('v') | Long("version") => {
@@ -88,6 +370,15 @@ pub fn parse() -> Result<Cli, Error> {
                     .into_boxed_str();
                 cli.config_path = Some(s);
             }
+            Value(v) if v == "query" => {
+                cli.command = Command::Query(parse_query(&mut parser)?);
+            }
+            Value(v) if v == "register" => {
+                cli.command = Command::Register(parse_register(&mut parser)?);
+            }
+            Value(v) if v == "bench" => {
+                cli.command = Command::Bench(parse_bench(&mut parser)?);
+            }
             _ => return Err(arg.unexpected().into()),
         }
     }
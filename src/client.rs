@@ -1,5 +1,6 @@
 use std::fmt;
 use std::io;
+use std::net::SocketAddrV4;
 use std::ops::Deref;
 use std::str;
 
@@ -36,21 +37,31 @@ pub enum Packet<'a> {
     Challenge(Option<u32>),
     ServerAdd(Option<u32>, ServerInfo<&'a str>),
     ServerRemove,
-    QueryServers(Region, Filter<'a>),
+    /// Region, address of the last server of the previous page and filter.
+    QueryServers(Region, Option<SocketAddrV4>, Filter<'a>),
+    /// Xash3D FWGS query, the filter may contain `clver` and `key`.
+    XashQueryServers(Region, Option<SocketAddrV4>, Filter<'a>),
     ServerInfo,
 }
 
 impl<'a> Packet<'a> {
+    /// Short packet type name used in logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Challenge(_) => "challenge",
+            Self::ServerAdd(..) => "server_add",
+            Self::ServerRemove => "server_remove",
+            Self::QueryServers(..) => "query_servers",
+            Self::XashQueryServers(..) => "xash_query_servers",
+            Self::ServerInfo => "server_info",
+        }
+    }
+
     pub fn decode(s: &'a [u8]) -> Result<Self, Error> {
         match s {
-            [b'1', region, tail @ ..] => {
-                let region = Region::try_from(*region).map_err(|_| Error::InvalidPacket)?;
-                let (tail, _) = decode_cstr(tail)?;
-                let (tail, filter) = decode_cstr(tail)?;
-                if !tail.is_empty() {
-                    return Err(Error::InvalidPacket);
-                }
-                Ok(Self::QueryServers(region, Filter(filter)))
+            [b'1', tail @ ..] => {
+                let (region, seed, filter) = decode_query(tail)?;
+                Ok(Self::QueryServers(region, seed, filter))
             }
             [b'q', 0xff, b0, b1, b2, b3] => {
                 let challenge = u32::from_le_bytes([*b0, *b1, *b2, *b3]);
@@ -72,6 +83,33 @@ impl<'a> Packet<'a> {
             _ => Err(Error::InvalidPacket),
         }
     }
+
+    /// Decodes packets of the Xash3D FWGS dialect, which only differs in queries.
+    pub fn decode_xash(s: &'a [u8]) -> Result<Self, Error> {
+        match s {
+            [b'1', tail @ ..] => {
+                let (region, seed, filter) = decode_query(tail)?;
+                Ok(Self::XashQueryServers(region, seed, filter))
+            }
+            _ => Self::decode(s),
+        }
+    }
+}
+
+/// Decodes region, seed and filter, the first page has seed `0.0.0.0:0`.
+fn decode_query(s: &[u8]) -> Result<(Region, Option<SocketAddrV4>, Filter<'_>), Error> {
+    let (region, tail) = s.split_first().ok_or(Error::InvalidPacket)?;
+    let region = Region::try_from(*region).map_err(|_| Error::InvalidPacket)?;
+    let (tail, seed) = decode_cstr(tail)?;
+    let (tail, filter) = decode_cstr(tail)?;
+    if !tail.is_empty() {
+        return Err(Error::InvalidPacket);
+    }
+    let seed = str::from_utf8(seed)
+        .ok()
+        .and_then(|s| s.parse::<SocketAddrV4>().ok())
+        .filter(|i| !i.ip().is_unspecified());
+    Ok((region, seed, Filter(filter)))
 }
 
 fn decode_cstr(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
@@ -80,3 +118,35 @@ fn decode_cstr(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
         .ok_or(Error::InvalidPacket)
         .map(|offset| (&data[offset + 1..], &data[..offset]))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_query() {
+        match Packet::decode(b"1\x030.0.0.0:0\0\\gamedir\\valve\0") {
+            Ok(Packet::QueryServers(Region::Europe, None, filter)) => {
+                assert_eq!(&*filter, b"\\gamedir\\valve");
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+        assert!(matches!(
+            Packet::decode(b"1\xff1.2.3.4:27015\0\0"),
+            Ok(Packet::QueryServers(Region::RestOfTheWorld, Some(_), _))
+        ));
+        assert!(Packet::decode(b"1").is_err());
+    }
+
+    #[test]
+    fn decode_xash_query() {
+        match Packet::decode_xash(b"1\x030.0.0.0:0\0\\gamedir\\valve\\key\\1f\0") {
+            Ok(Packet::XashQueryServers(Region::Europe, None, filter)) => {
+                assert_eq!(&*filter, b"\\gamedir\\valve\\key\\1f");
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+        assert!(matches!(Packet::decode_xash(b"b\n"), Ok(Packet::ServerRemove)));
+        assert!(Packet::decode_xash(b"1").is_err());
+    }
+}
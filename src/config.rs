@@ -1,6 +1,8 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::fs;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
 use std::path::Path;
 use std::str::from_utf8;
 
@@ -8,9 +10,20 @@ use log::LevelFilter;
 use serde::{de::Error as _, Deserialize, Deserializer};
 use thiserror::Error;
 
+use crate::cidr::{self, Ipv4Cidr};
+use crate::server_info::{Region, ServerFlags, ServerInfo, ServerType};
+
 pub const DEFAULT_MASTER_SERVER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
 pub const DEFAULT_MASTER_SERVER_PORT: u16 = 27010;
+pub const DEFAULT_DPMASTER_PORT: u16 = 27950;
+pub const DEFAULT_SERVER_THREADS: usize = 1;
 pub const DEFAULT_TIMEOUT: u32 = 300;
+pub const DEFAULT_MAX_CHALLENGES: usize = 65536;
+pub const DEFAULT_MAX_SERVERS: usize = 65536;
+pub const DEFAULT_LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_LOG_FILE_KEEP: u32 = 5;
+pub const DEFAULT_STATSD_PORT: u16 = 8125;
+pub const DEFAULT_STATSD_INTERVAL: u32 = 10;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -27,6 +40,14 @@ pub struct Config {
     pub log: LogConfig,
     #[serde(default)]
     pub server: ServerConfig,
+    #[serde(default)]
+    pub statsd: Option<StatsdConfig>,
+    #[serde(default)]
+    pub audit: Option<LogFileConfig>,
+    #[serde(default)]
+    pub dpmaster: Option<DpmasterConfig>,
+    #[serde(default)]
+    pub geoip: Option<GeoipConfig>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -35,16 +56,65 @@ pub struct LogConfig {
     #[serde(default = "default_log_level")]
     #[serde(deserialize_with = "deserialize_log_level")]
     pub level: LevelFilter,
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+    /// Per-target log levels, e.g. `"phantasma::filter" = "debug"`.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_log_targets")]
+    pub targets: Vec<(Box<str>, LevelFilter)>,
+    /// Peers for which trace logging of received packets is always enabled.
+    #[serde(default)]
+    pub trace_peers: Vec<Ipv4Cidr>,
 }
 
 impl Default for LogConfig {
     fn default() -> Self {
         Self {
             level: default_log_level(),
+            format: Default::default(),
+            file: None,
+            targets: Vec::new(),
+            trace_peers: Vec::new(),
         }
     }
 }
 
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable `[time] LEVEL - message` lines.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: Box<str>,
+    #[serde(default)]
+    pub rotate: Rotate,
+    #[serde(default = "default_log_file_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_log_file_keep")]
+    pub keep: u32,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotate {
+    /// Never rotate, rely on external tools like logrotate.
+    #[default]
+    Never,
+    /// Rotate when the file would grow beyond `max_size` bytes.
+    Size,
+    /// Rotate on the first write of a new day (UTC).
+    Daily,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ServerConfig {
@@ -52,8 +122,32 @@ pub struct ServerConfig {
     pub ip: IpAddr,
     #[serde(default = "default_server_port")]
     pub port: u16,
+    /// Number of packet processing threads, `0` uses every available core.
+    #[serde(default = "default_server_threads")]
+    pub threads: usize,
+    /// Use `recvmmsg`/`sendmmsg` on Linux.
+    #[serde(default)]
+    pub batch_io: bool,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// List servers announcing `\lan\1` only to clients on their network.
+    #[serde(default = "default_hide_lan")]
+    pub hide_lan: bool,
     #[serde(default)]
     pub timeout: TimeoutConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub regions: RegionsConfig,
+    #[serde(default)]
+    pub private: PrivateConfig,
+    #[serde(default)]
+    pub pinned: Vec<PinnedServer>,
+    #[serde(default)]
+    pub order: OrderConfig,
+    /// Allowed games, every game is allowed if empty.
+    #[serde(default)]
+    pub games: Vec<GameConfig>,
 }
 
 impl Default for ServerConfig {
@@ -61,7 +155,17 @@ impl Default for ServerConfig {
         Self {
             ip: default_server_ip(),
             port: default_server_port(),
+            threads: default_server_threads(),
+            batch_io: false,
+            protocol: Default::default(),
+            hide_lan: default_hide_lan(),
             timeout: Default::default(),
+            limits: Default::default(),
+            regions: Default::default(),
+            private: Default::default(),
+            pinned: Vec::new(),
+            order: Default::default(),
+            games: Vec::new(),
         }
     }
 }
@@ -84,10 +188,304 @@ impl Default for TimeoutConfig {
     }
 }
 
+/// Master protocol dialect spoken by clients.
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Valve GoldSrc/Source.
+    #[default]
+    Valve,
+    /// Xash3D FWGS, adds `clver` and `key` filter keys.
+    Xash,
+}
+
+/// What to do with a new entry when a table is full.
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Remove the oldest entry to make room.
+    Evict,
+    /// Drop the new entry, spoofed registrations can not push out servers.
+    #[default]
+    Reject,
+}
+
+/// Table size limits, `0` disables a limit.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    #[serde(default = "default_max_challenges")]
+    pub max_challenges: usize,
+    #[serde(default = "default_max_servers")]
+    pub max_servers: usize,
+    #[serde(default)]
+    pub max_servers_per_ip: usize,
+    /// Overrides of `max_servers_per_ip`, sorted from the most specific network.
+    #[serde(default, deserialize_with = "deserialize_cidr_limits")]
+    pub max_servers_per_cidr: Vec<(Ipv4Cidr, usize)>,
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+impl LimitsConfig {
+    /// Returns the maximum number of servers for `ip`, `0` for no limit.
+    pub fn servers_per_ip(&self, ip: Ipv4Addr) -> usize {
+        self.max_servers_per_cidr
+            .iter()
+            .find(|i| i.0.contains(ip))
+            .map_or(self.max_servers_per_ip, |i| i.1)
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_challenges: default_max_challenges(),
+            max_servers: default_max_servers(),
+            max_servers_per_ip: 0,
+            max_servers_per_cidr: Vec::new(),
+            overflow: Default::default(),
+        }
+    }
+}
+
+/// Neighbour regions added to queries with few results.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RegionsConfig {
+    /// Queries returning fewer servers also return neighbour regions, `0` disables fallback.
+    #[serde(default)]
+    pub min_results: usize,
+    #[serde(default, deserialize_with = "deserialize_region_fallback")]
+    pub fallback: Vec<(Region, Vec<Region>)>,
+}
+
+impl RegionsConfig {
+    /// Returns fallback regions of `region` in preference order.
+    pub fn neighbours(&self, region: Region) -> &[Region] {
+        self.fallback
+            .iter()
+            .find(|i| i.0 == region)
+            .map_or(&[], |i| &i.1)
+    }
+}
+
+/// How servers registering from private addresses are listed.
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivatePolicy {
+    /// List them to every client.
+    #[default]
+    Allow,
+    /// Do not register them.
+    Reject,
+    /// List them only to clients with private addresses.
+    Hide,
+    /// List them only to clients in the same private network.
+    SameRange,
+    /// List them with the public address from `rewrite` to public clients.
+    Rewrite,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PrivateConfig {
+    #[serde(default)]
+    pub policy: PrivatePolicy,
+    /// Public addresses of private networks, sorted from the most specific network.
+    #[serde(default, deserialize_with = "deserialize_rewrite")]
+    pub rewrite: Vec<(Ipv4Cidr, Ipv4Addr)>,
+}
+
+impl PrivateConfig {
+    /// Returns the address `server` is listed with to `client`, `None` if it is hidden.
+    pub fn listed_addr(&self, server: SocketAddrV4, client: Ipv4Addr) -> Option<SocketAddrV4> {
+        let range = match cidr::private_range(*server.ip()) {
+            Some(range) => range,
+            None => return Some(server),
+        };
+        match self.policy {
+            PrivatePolicy::Allow | PrivatePolicy::Reject => Some(server),
+            PrivatePolicy::Hide => cidr::private_range(client).map(|_| server),
+            PrivatePolicy::SameRange => range.contains(client).then_some(server),
+            PrivatePolicy::Rewrite if range.contains(client) => Some(server),
+            PrivatePolicy::Rewrite => self
+                .rewrite
+                .iter()
+                .find(|i| i.0.contains(*server.ip()))
+                .map(|i| SocketAddrV4::new(i.1, server.port())),
+        }
+    }
+}
+
+/// Game which may register, unset fields match any value.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GameConfig {
+    pub gamedir: Box<str>,
+    #[serde(default)]
+    pub product: Option<Box<str>>,
+    #[serde(default)]
+    pub min_protocol: Option<u8>,
+    #[serde(default)]
+    pub max_protocol: Option<u8>,
+}
+
+impl GameConfig {
+    pub fn matches(&self, info: &ServerInfo<&str>) -> bool {
+        *self.gamedir == *info.gamedir
+            && self.product.as_deref().is_none_or(|i| i == info.product)
+            && self.min_protocol.is_none_or(|i| info.protocol >= i)
+            && self.max_protocol.is_none_or(|i| info.protocol <= i)
+    }
+}
+
+/// Order of servers after featured ones in list responses.
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// By address.
+    #[default]
+    None,
+    /// Most players first.
+    Players,
+    /// Random order for each client and order snapshot, the same for every page.
+    Shuffle,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OrderConfig {
+    #[serde(default)]
+    pub policy: Order,
+    /// Servers listed first.
+    #[serde(default)]
+    pub featured: Vec<Ipv4Cidr>,
+}
+
+impl OrderConfig {
+    pub fn is_featured(&self, ip: Ipv4Addr) -> bool {
+        self.featured.iter().any(|i| i.contains(ip))
+    }
+}
+
+/// Server which is always listed, heartbeats replace the configured values.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PinnedServer {
+    pub addr: SocketAddrV4,
+    pub gamedir: Box<str>,
+    #[serde(default)]
+    pub map: Box<str>,
+    #[serde(default)]
+    pub version: Box<str>,
+    /// Defaults to `gamedir`.
+    #[serde(default)]
+    pub product: Option<Box<str>>,
+    #[serde(default = "default_pinned_protocol")]
+    pub protocol: u8,
+    #[serde(default, deserialize_with = "deserialize_region")]
+    pub region: Region,
+    #[serde(default)]
+    pub max: u8,
+    #[serde(default = "default_pinned_dedicated")]
+    pub dedicated: bool,
+    #[serde(default, deserialize_with = "deserialize_server_flags")]
+    pub flags: ServerFlags,
+}
+
+impl PinnedServer {
+    pub fn server_info(&self) -> ServerInfo<&str> {
+        ServerInfo {
+            gamedir: &self.gamedir,
+            map: &self.map,
+            version: &self.version,
+            product: self.product.as_deref().unwrap_or(&self.gamedir),
+            protocol: self.protocol,
+            server_type: match self.dedicated {
+                true => ServerType::Dedicated,
+                false => ServerType::Unknown,
+            },
+            region: self.region,
+            max: self.max,
+            flags: self.flags,
+            ..ServerInfo::default()
+        }
+    }
+}
+
+/// Listener for the Quake III Arena/DarkPlaces master protocol.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DpmasterConfig {
+    #[serde(default = "default_server_ip")]
+    pub ip: IpAddr,
+    #[serde(default = "default_dpmaster_port")]
+    pub port: u16,
+}
+
+/// Region assignment from a local GeoIP database.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GeoipConfig {
+    /// MaxMind `.mmdb` file or CSV of IP ranges.
+    pub path: Box<str>,
+    #[serde(default)]
+    pub mode: GeoipMode,
+}
+
+/// How the looked up region is combined with the region reported by servers.
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoipMode {
+    /// Keep the reported region.
+    Trust,
+    /// Always use the looked up region.
+    Override,
+    /// Use the looked up region only if the server reports `RestOfTheWorld`.
+    #[default]
+    Fill,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    #[serde(default = "default_statsd_host")]
+    pub host: Box<str>,
+    #[serde(default = "default_statsd_port")]
+    pub port: u16,
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: Box<str>,
+    #[serde(default = "default_statsd_interval")]
+    pub interval: u32,
+    #[serde(default)]
+    pub dogstatsd: bool,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            host: default_statsd_host(),
+            port: default_statsd_port(),
+            prefix: default_statsd_prefix(),
+            interval: default_statsd_interval(),
+            dogstatsd: false,
+        }
+    }
+}
+
 fn default_log_level() -> LevelFilter {
     LevelFilter::Warn
 }
 
+fn default_log_file_max_size() -> u64 {
+    DEFAULT_LOG_FILE_MAX_SIZE
+}
+
+fn default_log_file_keep() -> u32 {
+    DEFAULT_LOG_FILE_KEEP
+}
+
 fn default_server_ip() -> IpAddr {
     DEFAULT_MASTER_SERVER_IP
 }
@@ -96,10 +494,54 @@ fn default_server_port() -> u16 {
     DEFAULT_MASTER_SERVER_PORT
 }
 
+fn default_server_threads() -> usize {
+    DEFAULT_SERVER_THREADS
+}
+
+fn default_hide_lan() -> bool {
+    true
+}
+
+fn default_pinned_dedicated() -> bool {
+    true
+}
+
+fn default_pinned_protocol() -> u8 {
+    48
+}
+
+fn default_dpmaster_port() -> u16 {
+    DEFAULT_DPMASTER_PORT
+}
+
 fn default_timeout() -> u32 {
     DEFAULT_TIMEOUT
 }
 
+fn default_max_challenges() -> usize {
+    DEFAULT_MAX_CHALLENGES
+}
+
+fn default_max_servers() -> usize {
+    DEFAULT_MAX_SERVERS
+}
+
+fn default_statsd_host() -> Box<str> {
+    "127.0.0.1".into()
+}
+
+fn default_statsd_port() -> u16 {
+    DEFAULT_STATSD_PORT
+}
+
+fn default_statsd_prefix() -> Box<str> {
+    "phantasma".into()
+}
+
+fn default_statsd_interval() -> u32 {
+    DEFAULT_STATSD_INTERVAL
+}
+
 fn deserialize_log_level<'de, D>(deserializer: D) -> Result<LevelFilter, D::Error>
 where
     D: Deserializer<'de>,
@@ -108,6 +550,126 @@ where
     parse_log_level(&s).ok_or_else(|| D::Error::custom(format!("Invalid log level: \"{}\"", s)))
 }
 
+fn deserialize_log_targets<'de, D>(
+    deserializer: D,
+) -> Result<Vec<(Box<str>, LevelFilter)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map: BTreeMap<String, String> = Deserialize::deserialize(deserializer)?;
+    map.into_iter()
+        .map(|(target, s)| match parse_log_level(&s) {
+            Some(level) => Ok((target.into_boxed_str(), level)),
+            None => Err(D::Error::custom(format!(
+                "Invalid log level for \"{}\": \"{}\"",
+                target, s
+            ))),
+        })
+        .collect()
+}
+
+fn deserialize_cidr_limits<'de, D>(deserializer: D) -> Result<Vec<(Ipv4Cidr, usize)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map: BTreeMap<String, usize> = Deserialize::deserialize(deserializer)?;
+    let mut limits = map
+        .into_iter()
+        .map(|(cidr, limit)| match cidr.parse::<Ipv4Cidr>() {
+            Ok(cidr) => Ok((cidr, limit)),
+            Err(_) => Err(D::Error::custom(format!("Invalid CIDR \"{}\"", cidr))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    limits.sort_by_key(|i| Reverse(i.0.prefix()));
+    Ok(limits)
+}
+
+fn deserialize_rewrite<'de, D>(deserializer: D) -> Result<Vec<(Ipv4Cidr, Ipv4Addr)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map: BTreeMap<String, Ipv4Addr> = Deserialize::deserialize(deserializer)?;
+    let mut rewrite = map
+        .into_iter()
+        .map(|(cidr, ip)| match cidr.parse::<Ipv4Cidr>() {
+            Ok(cidr) => Ok((cidr, ip)),
+            Err(_) => Err(D::Error::custom(format!("Invalid CIDR \"{}\"", cidr))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    rewrite.sort_by_key(|i| Reverse(i.0.prefix()));
+    Ok(rewrite)
+}
+
+fn deserialize_region_fallback<'de, D>(
+    deserializer: D,
+) -> Result<Vec<(Region, Vec<Region>)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map: BTreeMap<String, Vec<String>> = Deserialize::deserialize(deserializer)?;
+    let region = |s: &str| {
+        parse_region(s).ok_or_else(|| D::Error::custom(format!("Invalid region \"{}\"", s)))
+    };
+    map.iter()
+        .map(|(from, to)| {
+            let to = to.iter().map(|i| region(i)).collect::<Result<_, _>>()?;
+            match region(from)? {
+                // queries of the rest of the world already list every region
+                Region::RestOfTheWorld => Err(D::Error::custom(
+                    "rest_of_the_world can not have fallback regions",
+                )),
+                from => Ok((from, to)),
+            }
+        })
+        .collect()
+}
+
+fn deserialize_region<'de, D>(deserializer: D) -> Result<Region, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    parse_region(&s).ok_or_else(|| D::Error::custom(format!("Invalid region \"{}\"", s)))
+}
+
+fn deserialize_server_flags<'de, D>(deserializer: D) -> Result<ServerFlags, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            "bots" => Ok(ServerFlags::BOTS),
+            "password" => Ok(ServerFlags::PASSWORD),
+            "secure" => Ok(ServerFlags::SECURE),
+            "lan" => Ok(ServerFlags::LAN),
+            "nat" => Ok(ServerFlags::NAT),
+            _ => Err(D::Error::custom(format!(
+                "Invalid server flag \"{}\"",
+                name
+            ))),
+        })
+        .collect()
+}
+
+/// Parses a region name like `middle_east` or a region code.
+fn parse_region(s: &str) -> Option<Region> {
+    let region = match s {
+        "us_east_coast" => Region::USEastCoast,
+        "us_west_coast" => Region::USWestCoast,
+        "south_america" => Region::SouthAmerica,
+        "europe" => Region::Europe,
+        "asia" => Region::Asia,
+        "australia" => Region::Australia,
+        "middle_east" => Region::MiddleEast,
+        "africa" => Region::Africa,
+        "rest_of_the_world" => Region::RestOfTheWorld,
+        _ => return s.parse().ok(),
+    };
+    Some(region)
+}
+
 pub fn parse_log_level(s: &str) -> Option<LevelFilter> {
     use LevelFilter as E;
 
@@ -145,3 +707,187 @@ pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
     let config = toml::from_str(data_str).map_err(Error::Toml)?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn servers_per_ip_overrides() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [server.limits]
+            max_servers_per_ip = 4
+            [server.limits.max_servers_per_cidr]
+            "10.0.0.0/8" = 32
+            "10.1.0.0/16" = 0
+            "10.1.2.3" = 8
+            "#,
+        )
+        .unwrap();
+        let limits = &cfg.server.limits;
+        assert_eq!(limits.servers_per_ip(Ipv4Addr::new(192, 0, 2, 1)), 4);
+        assert_eq!(limits.servers_per_ip(Ipv4Addr::new(10, 2, 0, 1)), 32);
+        assert_eq!(limits.servers_per_ip(Ipv4Addr::new(10, 1, 0, 1)), 0);
+        assert_eq!(limits.servers_per_ip(Ipv4Addr::new(10, 1, 2, 3)), 8);
+        assert_eq!(limits.overflow, Overflow::Reject);
+
+        let err = toml::from_str::<Config>(
+            r#"
+            [server.limits.max_servers_per_cidr]
+            "10.0.0.0/33" = 1
+            "#,
+        );
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn region_fallback() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [server.regions]
+            min_results = 10
+            [server.regions.fallback]
+            middle_east = ["europe", "asia"]
+            "7" = ["europe"]
+            "#,
+        )
+        .unwrap();
+        let regions = &cfg.server.regions;
+        assert_eq!(regions.min_results, 10);
+        assert_eq!(
+            regions.neighbours(Region::MiddleEast),
+            [Region::Europe, Region::Asia]
+        );
+        assert_eq!(regions.neighbours(Region::Africa), [Region::Europe]);
+        assert_eq!(regions.neighbours(Region::Europe), []);
+
+        let err = toml::from_str::<Config>(
+            r#"
+            [server.regions.fallback]
+            europe = ["mars"]
+            "#,
+        );
+        assert!(err.is_err());
+
+        let err = toml::from_str::<Config>(
+            r#"
+            [server.regions.fallback]
+            rest_of_the_world = ["europe"]
+            "#,
+        );
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn private_policy() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [server.private]
+            policy = "rewrite"
+            [server.private.rewrite]
+            "192.168.0.0/16" = "203.0.113.1"
+            "192.168.2.0/24" = "203.0.113.2"
+            "#,
+        )
+        .unwrap();
+        let mut private = cfg.server.private;
+        let server = SocketAddrV4::new(Ipv4Addr::new(192, 168, 2, 10), 27015);
+        let lan = Ipv4Addr::new(192, 168, 1, 5);
+        let public = Ipv4Addr::new(198, 51, 100, 7);
+
+        let listed = |private: &PrivateConfig, server, client| {
+            private.listed_addr(server, client).map(|i| i.to_string())
+        };
+        assert_eq!(
+            listed(&private, server, lan).as_deref(),
+            Some("192.168.2.10:27015")
+        );
+        assert_eq!(
+            listed(&private, server, public).as_deref(),
+            Some("203.0.113.2:27015")
+        );
+        let other = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 27015);
+        assert_eq!(listed(&private, other, public), None);
+        let global = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 27015);
+        assert_eq!(
+            listed(&private, global, public).as_deref(),
+            Some("1.2.3.4:27015")
+        );
+
+        private.policy = PrivatePolicy::Hide;
+        assert_eq!(listed(&private, server, public), None);
+        assert!(listed(&private, server, Ipv4Addr::new(10, 0, 0, 1)).is_some());
+
+        private.policy = PrivatePolicy::SameRange;
+        assert!(listed(&private, server, lan).is_some());
+        assert_eq!(listed(&private, server, Ipv4Addr::new(10, 0, 0, 1)), None);
+    }
+
+    #[test]
+    fn game_allowlist() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [[server.games]]
+            gamedir = "cstrike"
+            product = "cstrike"
+            min_protocol = 47
+            max_protocol = 48
+            [[server.games]]
+            gamedir = "valve"
+            "#,
+        )
+        .unwrap();
+        let allowed = |gamedir, product, protocol| {
+            let info = ServerInfo {
+                gamedir,
+                product,
+                protocol,
+                ..ServerInfo::default()
+            };
+            cfg.server.games.iter().any(|i| i.matches(&info))
+        };
+        assert!(allowed("cstrike", "cstrike", 48));
+        assert!(!allowed("cstrike", "cstrike", 49));
+        assert!(!allowed("cstrike", "czero", 48));
+        assert!(allowed("valve", "anything", 1));
+        assert!(!allowed("dod", "dod", 48));
+    }
+
+    #[test]
+    fn pinned_server_info() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [[server.games]]
+            gamedir = "cstrike"
+            product = "cstrike"
+            min_protocol = 47
+            [[server.games]]
+            gamedir = "valve"
+            product = "hl"
+            [[server.pinned]]
+            addr = "203.0.113.5:27015"
+            gamedir = "cstrike"
+            [[server.pinned]]
+            addr = "203.0.113.6:27015"
+            gamedir = "valve"
+            product = "hl"
+            [[server.pinned]]
+            addr = "203.0.113.7:27015"
+            gamedir = "cstrike"
+            protocol = 46
+            "#,
+        )
+        .unwrap();
+        let allowed = |pinned: &PinnedServer| {
+            let info = pinned.server_info();
+            cfg.server.games.iter().any(|i| i.matches(&info))
+        };
+        let pinned = &cfg.server.pinned;
+        assert_eq!(pinned[0].server_info().protocol, 48);
+        assert_eq!(pinned[0].server_info().product, "cstrike");
+        assert!(allowed(&pinned[0]));
+        assert!(allowed(&pinned[1]));
+        assert!(!allowed(&pinned[2]));
+    }
+}
@@ -0,0 +1,328 @@
+use std::net::SocketAddrV4;
+use std::str;
+
+use log::debug;
+use thiserror::Error;
+
+use crate::parser::{Error as ParserError, Parser};
+use crate::server::{Family, Server};
+use crate::server_info::ServerInfo;
+
+/// Prefix of every out-of-band packet.
+pub const HEADER: &[u8] = b"\xff\xff\xff\xff";
+
+/// The maximum size of server list datagrams.
+pub const MAX_PACKET_SIZE: usize = 1400;
+
+/// Game name of Quake III Arena, which omits it in heartbeats and queries.
+const DEFAULT_GAMENAME: &str = "Quake3Arena";
+
+/// Heartbeat tag sent by servers which shut down.
+pub const FLATLINE: &str = "flatline";
+
+const GETINFO: &[u8] = b"\xff\xff\xff\xffgetinfo ";
+const SERVERS_RESPONSE: &[u8] = b"\xff\xff\xff\xffgetserversResponse";
+const SERVERS_EXT_RESPONSE: &[u8] = b"\xff\xff\xff\xffgetserversExtResponse";
+const EOT: &[u8] = b"\\EOT\0\0\0";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Invalid packet data")]
+    InvalidPacket,
+    #[error(transparent)]
+    Parser(#[from] ParserError),
+}
+
+#[derive(Debug)]
+pub enum Packet<'a> {
+    /// `heartbeat <tag>` from a server.
+    Heartbeat(&'a str),
+    /// Server answer to `getinfo`.
+    InfoResponse(Info<'a>),
+    GetServers(Query<'a>),
+    GetServersExt(Query<'a>),
+}
+
+impl<'a> Packet<'a> {
+    /// Short packet type name used in logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Heartbeat(_) => "dp_heartbeat",
+            Self::InfoResponse(_) => "dp_info_response",
+            Self::GetServers(_) => "dp_getservers",
+            Self::GetServersExt(_) => "dp_getservers_ext",
+        }
+    }
+
+    pub fn decode(s: &'a [u8]) -> Result<Self, Error> {
+        let s = s.strip_prefix(HEADER).ok_or(Error::InvalidPacket)?;
+        if let Some(tail) = s.strip_prefix(b"heartbeat ") {
+            Ok(Self::Heartbeat(decode_line(tail)?))
+        } else if let Some(tail) = s.strip_prefix(b"infoResponse\n") {
+            Ok(Self::InfoResponse(Info::from_bytes(tail)?))
+        } else if let Some(tail) = s.strip_prefix(b"getserversExt ") {
+            Ok(Self::GetServersExt(Query::decode(
+                decode_line(tail)?,
+                true,
+            )?))
+        } else if let Some(tail) = s.strip_prefix(b"getservers ") {
+            Ok(Self::GetServers(Query::decode(decode_line(tail)?, false)?))
+        } else {
+            Err(Error::InvalidPacket)
+        }
+    }
+}
+
+/// Returns the text up to an optional trailing newline or NUL.
+fn decode_line(s: &[u8]) -> Result<&str, Error> {
+    let end = s
+        .iter()
+        .position(|&c| c == b'\n' || c == 0)
+        .unwrap_or(s.len());
+    str::from_utf8(&s[..end]).map_err(|_| Error::InvalidPacket)
+}
+
+/// Server info from `infoResponse`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Info<'a> {
+    pub challenge: &'a str,
+    pub gamename: &'a str,
+    pub protocol: u8,
+    pub clients: u8,
+    pub max: u8,
+    pub map: &'a str,
+}
+
+impl<'a> Info<'a> {
+    pub fn from_bytes(src: &'a [u8]) -> Result<Self, Error> {
+        let mut p = Parser::new(src);
+        let mut info = Info {
+            gamename: DEFAULT_GAMENAME,
+            ..Info::default()
+        };
+
+        loop {
+            let name = match p.parse_bytes() {
+                Ok(s) => s,
+                Err(ParserError::End) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            match name {
+                b"challenge" => info.challenge = p.parse()?,
+                b"gamename" => info.gamename = p.parse()?,
+                b"protocol" => info.protocol = p.parse()?,
+                b"clients" => info.clients = p.parse()?,
+                b"sv_maxclients" => info.max = p.parse()?,
+                b"mapname" => info.map = p.parse()?,
+                // hostname, gametype and many game specific keys
+                _ => {
+                    p.parse_bytes()?;
+                }
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Converts to heartbeat values shared with the Valve protocol.
+    pub fn server_info(&self) -> ServerInfo<&'a str> {
+        ServerInfo {
+            gamedir: self.gamename,
+            map: self.map,
+            product: self.gamename,
+            protocol: self.protocol,
+            players: self.clients,
+            max: self.max,
+            ..ServerInfo::default()
+        }
+    }
+}
+
+/// Client request from `getservers` or `getserversExt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query<'a> {
+    pub gamename: &'a str,
+    pub protocol: u8,
+    pub empty: bool,
+    pub full: bool,
+    /// IPv4 servers are requested, IPv6 servers are not supported.
+    pub ipv4: bool,
+}
+
+impl<'a> Query<'a> {
+    /// Decodes `[gamename] protocol [filters...]`, gamename is required by
+    /// `getserversExt`.
+    pub fn decode(s: &'a str, ext: bool) -> Result<Self, Error> {
+        let mut tokens = s.split(' ').filter(|i| !i.is_empty()).peekable();
+        let gamename = match tokens.peek() {
+            Some(i) if !ext && i.parse::<u8>().is_ok() => DEFAULT_GAMENAME,
+            Some(_) => tokens.next().unwrap(),
+            None => return Err(Error::InvalidPacket),
+        };
+        let protocol = tokens
+            .next()
+            .ok_or(Error::InvalidPacket)?
+            .parse()
+            .map_err(|_| Error::InvalidPacket)?;
+
+        let mut query = Query {
+            gamename,
+            protocol,
+            empty: false,
+            full: false,
+            ipv4: true,
+        };
+        let mut ipv4 = false;
+        let mut ipv6 = false;
+        for token in tokens {
+            match token {
+                "empty" => query.empty = true,
+                "full" => query.full = true,
+                "ipv4" => ipv4 = true,
+                "ipv6" => ipv6 = true,
+                _ => debug!("Ignored getservers filter \"{}\"", token),
+            }
+        }
+        // no address family means both
+        query.ipv4 = ipv4 || !ipv6;
+        Ok(query)
+    }
+
+    pub fn matches(&self, server: &Server) -> bool {
+        server.family == Family::Dpmaster
+            && *server.gamedir == *self.gamename
+            && server.protocol == self.protocol
+            && (self.empty || server.players > 0)
+            && (self.full || server.players < server.max)
+    }
+}
+
+pub fn encode_getinfo(challenge: u32) -> Vec<u8> {
+    let mut buf = GETINFO.to_vec();
+    buf.extend_from_slice(format!("{:08x}", challenge).as_bytes());
+    buf
+}
+
+/// Parses the challenge sent in `getinfo` back from `infoResponse`.
+pub fn decode_challenge(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Encodes the server list, the last datagram ends with `\EOT\0\0\0`.
+pub fn encode_servers<I>(ext: bool, iter: I) -> Vec<Vec<u8>>
+where
+    I: IntoIterator<Item = SocketAddrV4>,
+{
+    let header = if ext {
+        SERVERS_EXT_RESPONSE
+    } else {
+        SERVERS_RESPONSE
+    };
+    let mut packets = vec![header.to_vec()];
+    for addr in iter {
+        let buf = packets.last_mut().unwrap();
+        if buf.len() + 7 + EOT.len() > MAX_PACKET_SIZE {
+            packets.push(header.to_vec());
+        }
+        let buf = packets.last_mut().unwrap();
+        buf.push(b'\\');
+        buf.extend_from_slice(&addr.ip().octets());
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+    }
+    packets.last_mut().unwrap().extend_from_slice(EOT);
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_heartbeat() {
+        match Packet::decode(b"\xff\xff\xff\xffheartbeat DarkPlaces\n") {
+            Ok(Packet::Heartbeat(tag)) => assert_eq!(tag, "DarkPlaces"),
+            p => panic!("unexpected {:?}", p),
+        }
+        assert!(Packet::decode(b"heartbeat DarkPlaces\n").is_err());
+    }
+
+    #[test]
+    fn decode_info_response() {
+        let src = b"\xff\xff\xff\xffinfoResponse\n\
+            \\sv_maxclients\\16\\gamename\\Xonotic\\protocol\\3\\clients\\2\
+            \\hostname\\test\\mapname\\stormkeep\\challenge\\0000002a";
+        match Packet::decode(src) {
+            Ok(Packet::InfoResponse(info)) => {
+                assert_eq!(
+                    info,
+                    Info {
+                        challenge: "0000002a",
+                        gamename: "Xonotic",
+                        protocol: 3,
+                        clients: 2,
+                        max: 16,
+                        map: "stormkeep",
+                    }
+                );
+                assert_eq!(decode_challenge(info.challenge), Some(42));
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+
+        let info = Info::from_bytes(b"\\protocol\\68\\challenge\\1").unwrap();
+        assert_eq!(info.gamename, "Quake3Arena");
+    }
+
+    #[test]
+    fn decode_query() {
+        let query = |s: &'static [u8]| match Packet::decode(s) {
+            Ok(Packet::GetServers(q) | Packet::GetServersExt(q)) => q,
+            p => panic!("unexpected {:?}", p),
+        };
+
+        let q = query(b"\xff\xff\xff\xffgetservers 68 empty full");
+        assert_eq!(q.gamename, "Quake3Arena");
+        assert_eq!(q.protocol, 68);
+        assert!(q.empty && q.full && q.ipv4);
+
+        let q = query(b"\xff\xff\xff\xffgetservers DarkPlaces-Quake 3 empty\0");
+        assert_eq!(q.gamename, "DarkPlaces-Quake");
+        assert_eq!(q.protocol, 3);
+        assert!(q.empty && !q.full);
+
+        let q = query(b"\xff\xff\xff\xffgetserversExt Xonotic 3 ipv6");
+        assert_eq!(q.gamename, "Xonotic");
+        assert!(!q.ipv4);
+
+        assert!(Packet::decode(b"\xff\xff\xff\xffgetserversExt 3").is_err());
+        assert!(Packet::decode(b"\xff\xff\xff\xffgetservers Xonotic").is_err());
+    }
+
+    #[test]
+    fn encode_server_list() {
+        let addr = SocketAddrV4::new([1, 2, 3, 4].into(), 0x6d2e);
+        let packets = encode_servers(false, [addr]);
+        assert_eq!(
+            packets,
+            [b"\xff\xff\xff\xffgetserversResponse\\\x01\x02\x03\x04\x6d\x2e\\EOT\0\0\0"]
+        );
+
+        let packets = encode_servers(true, []);
+        assert_eq!(
+            packets,
+            [b"\xff\xff\xff\xffgetserversExtResponse\\EOT\0\0\0"]
+        );
+
+        let packets = encode_servers(false, vec![addr; 500]);
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|i| i.len() <= MAX_PACKET_SIZE));
+        assert!(packets[0].ends_with(&[0x6d, 0x2e]));
+        assert!(packets[2].ends_with(EOT));
+        let total: usize = packets
+            .iter()
+            .map(|i| i.len() - SERVERS_RESPONSE.len())
+            .sum();
+        assert_eq!(total, 500 * 7 + EOT.len());
+    }
+}
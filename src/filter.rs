@@ -36,6 +36,8 @@ bitflags! {
         const NOR          = 1 << 11;
         /// Servers matching all of the following [x] conditions should not be returned
         const NAND          = 1 << 12;
+        /// Servers that are behind NAT (Xash3D FWGS)
+        const NAT           = 1 << 13;
     }
 }
 
@@ -53,6 +55,7 @@ impl<T> From<&ServerInfo<T>> for FilterFlags {
         flags.set(Self::NOPLAYERS, info.players == 0);
         flags.set(Self::LAN, info.flags.contains(ServerFlags::LAN));
         flags.set(Self::BOTS, info.flags.contains(ServerFlags::BOTS));
+        flags.set(Self::NAT, info.flags.contains(ServerFlags::NAT));
 
         flags
     }
@@ -86,6 +89,10 @@ pub struct Filter<'a> {
     pub napp: Option<u32>,
     /// Return only one server for each unique IP address matched
     pub collapse_addr_hash: bool,
+    /// Xash3D FWGS client version, servers with a newer version are not returned
+    pub clver: Option<&'a str>,
+    /// Xash3D FWGS number that master must echo back in the list response
+    pub key: Option<u32>,
 
     pub flags: FilterFlags,
     pub flags_mask: FilterFlags,
@@ -97,6 +104,7 @@ impl Filter<'_> {
         self.flags_mask.insert(flag);
     }
 
+    #[allow(clippy::unnecessary_map_or)]
     pub fn matches(&self, addr: SocketAddrV4, server: &Server) -> bool {
         if (server.flags & self.flags_mask) != self.flags {
             return false;
@@ -110,6 +118,11 @@ impl Filter<'_> {
         if self.version_match.map_or(false, |i| &*server.version != i) {
             return false;
         }
+        let version = || parse_version(&server.version);
+        let clver = self.clver.and_then(parse_version);
+        if clver.is_some_and(|clver| version().is_some_and(|i| i > clver)) {
+            return false;
+        }
         if let Some(a) = self.gameaddr {
             if addr.ip() != a.ip() {
                 return false;
@@ -122,18 +135,26 @@ impl Filter<'_> {
     }
 }
 
+/// Parses the major and minor number of a version like `0.21`.
+fn parse_version(s: &str) -> Option<(u32, u32)> {
+    let (major, tail) = s.split_once('.')?;
+    let minor = tail.split('.').next()?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
 impl<'a> Filter<'a> {
     pub fn from_bytes(src: &'a [u8]) -> Result<Self, ParserError> {
         let mut parser = Parser::new(src);
         let filter = parser.parse()?;
         Ok(filter)
     }
-}
 
-impl<'a> ParseValue<'a> for Filter<'a> {
-    type Err = ParserError;
+    /// Parses a filter of the Xash3D FWGS dialect, which adds `clver` and `key`.
+    pub fn from_bytes_xash(src: &'a [u8]) -> Result<Self, ParserError> {
+        Self::parse_fields(&mut Parser::new(src), true)
+    }
 
-    fn parse(p: &mut Parser<'a>) -> Result<Self, Self::Err> {
+    fn parse_fields(p: &mut Parser<'a>, xash: bool) -> Result<Self, ParserError> {
         let mut filter = Self::default();
 
         loop {
@@ -175,6 +196,14 @@ impl<'a> ParseValue<'a> for Filter<'a> {
                 }
                 b"lan" => filter.insert_flag(FilterFlags::LAN, p.parse()?),
                 b"bots" => filter.insert_flag(FilterFlags::BOTS, p.parse()?),
+                b"nat" => filter.insert_flag(FilterFlags::NAT, p.parse()?),
+                b"clver" if xash => filter.clver = Some(p.parse()?),
+                b"key" if xash => {
+                    let s = p.parse::<&str>()?;
+                    let key =
+                        u32::from_str_radix(s, 16).map_err(|_| ParserError::InvalidInteger)?;
+                    filter.key = Some(key);
+                }
                 _ => {
                     // skip unknown fields
                     let value = p.parse_bytes()?;
@@ -191,12 +220,22 @@ impl<'a> ParseValue<'a> for Filter<'a> {
     }
 }
 
+impl<'a> ParseValue<'a> for Filter<'a> {
+    type Err = ParserError;
+
+    fn parse(p: &mut Parser<'a>) -> Result<Self, Self::Err> {
+        Self::parse_fields(p, false)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use std::net::Ipv4Addr;
 
+    use crate::intern::Interner;
+
     macro_rules! tests {
         ($($name:ident$(($($predefined_f:ident: $predefined_v:expr),+ $(,)?))? {
             $($src:expr => {
@@ -357,6 +396,15 @@ mod tests {
                 flags: FilterFlags::BOTS,
             }
         }
+        parse_nat(flags_mask: FilterFlags::NAT) {
+            b"\\nat\\0" => {}
+            b"\\nat\\1" => {
+                flags: FilterFlags::NAT,
+            }
+        }
+        parse_xash_keys {
+            b"\\clver\\0.21\\key\\deadbeef" => {}
+        }
 
         parse_all {
             b"\
@@ -384,6 +432,7 @@ mod tests {
               \\white\\1\
               \\nor\\1\
               \\nand\\1\
+              \\nat\\1\
             " => {
                 gamedir: Some("valve"),
                 map: Some("crossfire"),
@@ -402,12 +451,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_xash() {
+        assert_eq!(
+            Filter::from_bytes_xash(b"\\gamedir\\valve\\clver\\0.21\\key\\1f"),
+            Ok(Filter {
+                gamedir: Some("valve"),
+                clver: Some("0.21"),
+                key: Some(0x1f),
+                ..Filter::default()
+            })
+        );
+        assert!(Filter::from_bytes_xash(b"\\key\\xyz").is_err());
+        // Valve clients do not send Xash3D FWGS keys
+        assert!(Filter::from_bytes(b"\\key\\xyz").is_ok());
+    }
+
     macro_rules! servers {
         ($($addr:expr => $info:expr $(=> $func:expr)?)+) => (
             [$({
                 let addr = $addr.parse::<SocketAddrV4>().unwrap();
                 let (_, info, _) = ServerInfo::<&str>::from_bytes($info).unwrap();
-                let server = Server::new(&info);
+                let server = Server::new(&info, &mut Interner::default());
                 $(
                     let mut server = server;
                     let func: fn(&mut Server) = $func;
@@ -542,6 +607,18 @@ mod tests {
         matches!(servers, b"\\bots\\1", 2);
     }
 
+    #[test]
+    fn match_nat() {
+        let servers = servers! {
+            "0.0.0.0:0" => b""
+            "0.0.0.0:0" => b"\\nat\\0"
+            "0.0.0.0:0" => b"\\nat\\1"
+        };
+        matches!(servers, b"", 0, 1, 2);
+        matches!(servers, b"\\nat\\0", 0, 1);
+        matches!(servers, b"\\nat\\1", 2);
+    }
+
     #[test]
     fn match_white() {
         let servers = servers! {
@@ -603,4 +680,24 @@ mod tests {
         matches!(servers, b"\\map\\de_dust", 2);
         matches!(servers, b"\\map\\cs_office", 3);
     }
+
+    #[test]
+    fn match_clver() {
+        let servers = servers! {
+            "0.0.0.0:0" => b"\\version\\0.19.2"
+            "0.0.0.0:0" => b"\\version\\0.21"
+            "0.0.0.0:0" => b"\\version\\0.22"
+            "0.0.0.0:0" => b"\\version\\1.1.2.7"
+            "0.0.0.0:0" => b""
+        };
+        let matches = |filter| {
+            let filter = Filter::from_bytes_xash(filter).unwrap();
+            let iter = servers.iter().enumerate();
+            let iter = iter.filter(|(_, (addr, server))| filter.matches(*addr, server));
+            iter.map(|(i, _)| i).collect::<Vec<_>>()
+        };
+        assert_eq!(matches(b""), [0, 1, 2, 3, 4]);
+        assert_eq!(matches(b"\\clver\\0.21"), [0, 1, 4]);
+        assert_eq!(matches(b"\\clver\\dev"), [0, 1, 2, 3, 4]);
+    }
 }
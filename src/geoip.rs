@@ -0,0 +1,227 @@
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use log::debug;
+use thiserror::Error;
+
+use crate::server_info::Region;
+
+/// ISO 3166 country codes of each region, North America is split by longitude.
+const SOUTH_AMERICA: &str = "AR BO BR CL CO EC FK GF GY PE PY SR UY VE";
+const EUROPE: &str = "AD AL AM AT AX AZ BA BE BG BY CH CY CZ DE DK EE ES FI FO FR GB GE GG GI \
+    GR HR HU IE IM IS IT JE LI LT LU LV MC MD ME MK MT NL NO PL PT RO RS RU SE SI SJ SK SM TR UA \
+    VA XK";
+const ASIA: &str = "AF BD BN BT CN HK ID IN JP KG KH KP KR KZ LA LK MM MN MO MV MY NP PH PK SG \
+    TH TJ TL TM TW UZ VN";
+const AUSTRALIA: &str = "AS AU CK FJ FM GU KI MH MP NC NR NU NZ PF PG PW SB TO TV VU WS";
+const MIDDLE_EAST: &str = "AE BH IL IQ IR JO KW LB OM PS QA SA SY YE";
+const AFRICA: &str = "AO BF BI BJ BW CD CF CG CI CM CV DJ DZ EG EH ER ET GA GH GM GN GQ GW KE \
+    KM LR LS LY MA MG ML MR MU MW MZ NA NE NG RE RW SC SD SH SL SN SO SS ST SZ TD TG TN TZ UG YT \
+    ZA ZM ZW";
+const NORTH_AMERICA: &str = "AG AI BB BM BS BZ CA CR CU DM DO GD GL GP GT HN HT JM KN KY LC MQ \
+    MX NI PA PM PR SV TC TT US VC VG VI";
+
+/// Longitude which separates US west coast from east coast servers.
+const US_WEST_LONGITUDE: f64 = -100.0;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Invalid CSV at line {0}")]
+    InvalidLine(usize),
+    #[cfg(feature = "mmdb")]
+    #[error(transparent)]
+    Mmdb(#[from] maxminddb::MaxMindDBError),
+    #[cfg(not(feature = "mmdb"))]
+    #[error("MMDB support is not compiled in")]
+    MmdbDisabled,
+}
+
+/// Local IP to region database.
+pub enum GeoIp {
+    /// Sorted `(first, last, region)` ranges from a CSV file.
+    Ranges(Vec<(u32, u32, Region)>),
+    #[cfg(feature = "mmdb")]
+    Mmdb(maxminddb::Reader<Vec<u8>>),
+}
+
+impl GeoIp {
+    /// Opens a MaxMind database if the file ends with `.mmdb`, otherwise
+    /// reads CSV ranges.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|i| i == "mmdb") {
+            #[cfg(feature = "mmdb")]
+            return Ok(Self::Mmdb(maxminddb::Reader::open_readfile(path)?));
+            #[cfg(not(feature = "mmdb"))]
+            return Err(Error::MmdbDisabled);
+        }
+        Self::from_csv(&fs::read_to_string(path)?)
+    }
+
+    /// Parses `first,last,region` lines, extra columns are ignored.
+    ///
+    /// Addresses are dotted or integers, region is a Valve region code or a
+    /// country code. IPv6 ranges and unknown countries are skipped.
+    pub fn from_csv(s: &str) -> Result<Self, Error> {
+        let mut ranges = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split(',').map(|i| i.trim().trim_matches('"'));
+            let (first, last, code) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(first), Some(last), Some(code)) => (first, last, code),
+                _ => return Err(Error::InvalidLine(n + 1)),
+            };
+            if first.contains(':') {
+                continue;
+            }
+            let (first, last) = match (parse_ip(first), parse_ip(last)) {
+                (Some(first), Some(last)) if first <= last => (first, last),
+                _ => return Err(Error::InvalidLine(n + 1)),
+            };
+            let region = match code.parse::<Region>() {
+                Ok(region) => region,
+                Err(_) => match country_region(code, None) {
+                    Some(region) => region,
+                    None => {
+                        debug!("GeoIP: skipped unknown country \"{}\"", code);
+                        continue;
+                    }
+                },
+            };
+            ranges.push((first, last, region));
+        }
+        ranges.sort_unstable_by_key(|i| i.0);
+        Ok(Self::Ranges(ranges))
+    }
+
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<Region> {
+        match self {
+            Self::Ranges(ranges) => {
+                let ip = u32::from(ip);
+                let i = ranges.partition_point(|i| i.0 <= ip);
+                let (_, last, region) = ranges.get(i.checked_sub(1)?)?;
+                (ip <= *last).then_some(*region)
+            }
+            #[cfg(feature = "mmdb")]
+            Self::Mmdb(reader) => {
+                use maxminddb::geoip2::City;
+
+                // country databases decode as cities without city fields
+                let city: City = reader.lookup(ip.into()).ok()?;
+                let longitude = city.location.and_then(|i| i.longitude);
+                let country = city.country.and_then(|i| i.iso_code);
+                let continent = city.continent.and_then(|i| i.code);
+                country
+                    .and_then(|i| country_region(i, longitude))
+                    .or_else(|| continent_region(continent?, longitude))
+            }
+        }
+    }
+}
+
+fn parse_ip(s: &str) -> Option<u32> {
+    s.parse::<Ipv4Addr>()
+        .map(u32::from)
+        .or_else(|_| s.parse::<u32>())
+        .ok()
+}
+
+fn north_america(longitude: Option<f64>) -> Region {
+    match longitude {
+        Some(i) if i < US_WEST_LONGITUDE => Region::USWestCoast,
+        _ => Region::USEastCoast,
+    }
+}
+
+/// Maps an ISO 3166 country code to a region.
+fn country_region(code: &str, longitude: Option<f64>) -> Option<Region> {
+    let code = code.to_ascii_uppercase();
+    let has = |list: &str| list.split_whitespace().any(|i| i == code);
+    let region = match () {
+        _ if has(NORTH_AMERICA) => north_america(longitude),
+        _ if has(SOUTH_AMERICA) => Region::SouthAmerica,
+        _ if has(EUROPE) => Region::Europe,
+        _ if has(ASIA) => Region::Asia,
+        _ if has(AUSTRALIA) => Region::Australia,
+        _ if has(MIDDLE_EAST) => Region::MiddleEast,
+        _ if has(AFRICA) => Region::Africa,
+        _ => return None,
+    };
+    Some(region)
+}
+
+/// Maps a GeoIP2 continent code to a region.
+#[cfg(feature = "mmdb")]
+fn continent_region(code: &str, longitude: Option<f64>) -> Option<Region> {
+    let region = match code {
+        "NA" => north_america(longitude),
+        "SA" => Region::SouthAmerica,
+        "EU" => Region::Europe,
+        "AS" => Region::Asia,
+        "OC" => Region::Australia,
+        "AF" => Region::Africa,
+        _ => return None,
+    };
+    Some(region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_lookup() {
+        let db = GeoIp::from_csv(
+            "# first,last,region\n\
+             1.0.0.0,1.0.0.255,AU\n\
+             \"16777472\",\"16778239\",\"CN\",\"China\"\n\
+             2.0.0.0,2.255.255.255,fr\n\
+             10.0.0.0,10.0.0.255,6\n\
+             11.0.0.0,11.0.0.255,ZZ\n\
+             2001:200::,2001:200:ffff::,JP\n",
+        )
+        .unwrap();
+        assert_eq!(
+            db.lookup(Ipv4Addr::new(1, 0, 0, 7)),
+            Some(Region::Australia)
+        );
+        assert_eq!(db.lookup(Ipv4Addr::new(1, 0, 1, 0)), Some(Region::Asia));
+        assert_eq!(db.lookup(Ipv4Addr::new(2, 1, 2, 3)), Some(Region::Europe));
+        assert_eq!(
+            db.lookup(Ipv4Addr::new(10, 0, 0, 1)),
+            Some(Region::MiddleEast)
+        );
+        assert_eq!(db.lookup(Ipv4Addr::new(11, 0, 0, 1)), None);
+        assert_eq!(db.lookup(Ipv4Addr::new(0, 255, 255, 255)), None);
+        assert_eq!(db.lookup(Ipv4Addr::new(3, 0, 0, 0)), None);
+
+        assert!(matches!(
+            GeoIp::from_csv("1.0.0.0,AU\n"),
+            Err(Error::InvalidLine(1))
+        ));
+        assert!(matches!(
+            GeoIp::from_csv("\n1.0.0.9,1.0.0.0,AU\n"),
+            Err(Error::InvalidLine(2))
+        ));
+    }
+
+    #[test]
+    fn country_regions() {
+        assert_eq!(country_region("US", None), Some(Region::USEastCoast));
+        assert_eq!(
+            country_region("US", Some(-122.4)),
+            Some(Region::USWestCoast)
+        );
+        assert_eq!(country_region("BR", None), Some(Region::SouthAmerica));
+        assert_eq!(country_region("ZA", None), Some(Region::Africa));
+        assert_eq!(country_region("EU", None), None);
+    }
+}
@@ -0,0 +1,119 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Shared immutable string, cloning only increments the refcount.
+///
+/// A thin pointer to keep `Server` small, the extra indirection is cheap
+/// compared to the allocations it saves.
+#[derive(Clone)]
+pub struct Atom(Arc<Box<str>>);
+
+impl Deref for Atom {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Atom {
+    fn borrow(&self) -> &str {
+        self
+    }
+}
+
+impl PartialEq for Atom {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Atom {}
+
+impl Hash for Atom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for Atom {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, fmt)
+    }
+}
+
+/// Deduplicates strings shared by many servers like gamedir and map names.
+#[derive(Default)]
+pub struct Interner {
+    strings: HashSet<Atom>,
+    bytes: usize,
+}
+
+impl Interner {
+    pub fn intern(&mut self, s: &str) -> Atom {
+        if let Some(atom) = self.strings.get(s) {
+            return atom.clone();
+        }
+        let atom = Atom(Arc::new(s.into()));
+        self.strings.insert(atom.clone());
+        self.bytes += s.len();
+        atom
+    }
+
+    /// Drops strings not referenced outside of the interner, returns the
+    /// number of removed strings.
+    pub fn collect(&mut self) -> usize {
+        let old = self.strings.len();
+        let mut bytes = self.bytes;
+        self.strings.retain(|i| {
+            let used = Arc::strong_count(&i.0) > 1;
+            if !used {
+                bytes -= i.len();
+            }
+            used
+        });
+        self.bytes = bytes;
+        old - self.strings.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Approximate heap usage of interned strings.
+    pub fn memory(&self) -> usize {
+        let per_string = size_of::<Atom>() + size_of::<Box<str>>() + 2 * size_of::<usize>();
+        self.bytes + self.strings.capacity() * per_string
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_and_collect() {
+        let mut strings = Interner::default();
+        let a = strings.intern("cstrike");
+        let b = strings.intern("cstrike");
+        let c = strings.intern("valve");
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_eq!(&*c, "valve");
+        assert_eq!(strings.len(), 2);
+
+        drop(c);
+        assert_eq!(strings.collect(), 1);
+        assert_eq!(strings.len(), 1);
+        assert_eq!(strings.bytes, "cstrike".len());
+
+        drop(a);
+        assert_eq!(strings.collect(), 0);
+        drop(b);
+        assert_eq!(strings.collect(), 1);
+        assert_eq!(strings.bytes, 0);
+    }
+}
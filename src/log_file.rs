@@ -0,0 +1,179 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{LogFileConfig, Rotate};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Append-only line oriented file with optional rotation.
+///
+/// The file is reopened on SIGHUP and SIGUSR1 so that external tools like
+/// logrotate can move it away.
+pub struct LogFile {
+    path: PathBuf,
+    rotate: Rotate,
+    max_size: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+    day: u64,
+    reopen: Arc<AtomicBool>,
+}
+
+impl LogFile {
+    pub fn open(cfg: &LogFileConfig) -> io::Result<Self> {
+        let path = PathBuf::from(&*cfg.path);
+        let (file, size) = open_append(&path)?;
+        let reopen = Arc::new(AtomicBool::new(false));
+        register_reopen_signals(&reopen)?;
+
+        Ok(Self {
+            path,
+            rotate: cfg.rotate,
+            max_size: cfg.max_size,
+            keep: cfg.keep,
+            file,
+            size,
+            day: today(),
+            reopen,
+        })
+    }
+
+    /// Appends `line` and a trailing newline, rotating the file beforehand if needed.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            self.reopen()?;
+        }
+
+        let len = line.len() as u64 + 1;
+        let rotate = match self.rotate {
+            Rotate::Never => false,
+            Rotate::Size => self.size > 0 && self.size + len > self.max_size,
+            Rotate::Daily => self.day != today(),
+        };
+        if rotate {
+            self.rotate()?;
+        }
+
+        let mut buf = Vec::with_capacity(line.len() + 1);
+        buf.extend_from_slice(line.as_bytes());
+        buf.push(b'\n');
+        self.file.write_all(&buf)?;
+        self.size += len;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let (file, size) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Shifts `path.N` to `path.N+1`, dropping files beyond the retention count.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            remove_if_exists(&self.path)?;
+        } else {
+            remove_if_exists(&backup_path(&self.path, self.keep))?;
+            for i in (1..self.keep).rev() {
+                rename_if_exists(&backup_path(&self.path, i), &backup_path(&self.path, i + 1))?;
+            }
+            rename_if_exists(&self.path, &backup_path(&self.path, 1))?;
+        }
+        self.day = today();
+        self.reopen()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn backup_path(path: &Path, n: u32) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{}", n));
+    PathBuf::from(s)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Days since the Unix epoch (UTC).
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / SECONDS_PER_DAY)
+}
+
+#[cfg(unix)]
+fn register_reopen_signals(flag: &Arc<AtomicBool>) -> io::Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGUSR1};
+
+    signal_hook::flag::register(SIGHUP, flag.clone())?;
+    signal_hook::flag::register(SIGUSR1, flag.clone())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn register_reopen_signals(_flag: &Arc<AtomicBool>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("phantasma-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("test.log")
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let path = temp_path("rotate");
+        let cfg = LogFileConfig {
+            path: path.to_str().unwrap().into(),
+            rotate: Rotate::Size,
+            max_size: 8,
+            keep: 2,
+        };
+        let mut file = LogFile::open(&cfg).unwrap();
+        for line in ["aaaa", "bbbb", "cccc", "dddd"] {
+            file.write_line(line).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddd\n");
+        assert_eq!(fs::read_to_string(backup_path(&path, 1)).unwrap(), "cccc\n");
+        assert_eq!(fs::read_to_string(backup_path(&path, 2)).unwrap(), "bbbb\n");
+        assert!(!backup_path(&path, 3).exists());
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
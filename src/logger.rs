@@ -1,35 +1,344 @@
-use log::{LevelFilter, Metadata, Record};
+use std::fmt::{self, Write as _};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str;
+use std::sync::Mutex;
+#[cfg(not(feature = "logtime"))]
+use std::time::{SystemTime, UNIX_EPOCH};
 
-struct Logger;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
 
-impl Logger {}
+use crate::cidr::Ipv4Cidr;
+use crate::config::{LogConfig, LogFormat};
+use crate::log_file::LogFile;
 
-impl log::Log for Logger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+struct Logger {
+    level: LevelFilter,
+    /// Sorted from the most specific target to the least specific.
+    targets: Box<[(Box<str>, LevelFilter)]>,
+    trace_peers: Box<[Ipv4Cidr]>,
+    format: LogFormat,
+    file: Option<Mutex<LogFile>>,
+    /// Write to stderr instead of stdout when there is no file.
+    stderr: bool,
+}
+
+impl Logger {
+    fn new(cfg: &LogConfig, file: Option<LogFile>) -> Self {
+        let mut targets = cfg.targets.clone();
+        targets.sort_by_key(|i| std::cmp::Reverse(i.0.len()));
+
+        Self {
+            level: cfg.level,
+            targets: targets.into(),
+            trace_peers: cfg.trace_peers.clone().into(),
+            format: cfg.format,
+            file: file.map(Mutex::new),
+            stderr: false,
+        }
     }
 
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            #[cfg(not(feature = "logtime"))]
-            println!("{} - {}", record.level(), record.args());
-
-            #[cfg(feature = "logtime")]
-            {
-                let dt = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
-                println!("[{}] {} - {}", dt, record.level(), record.args());
+    /// The highest level any record may be logged at.
+    fn max_level(&self) -> LevelFilter {
+        let targets = self.targets.iter().map(|i| i.1);
+        let peers = (!self.trace_peers.is_empty()).then_some(LevelFilter::Trace);
+        targets.chain(peers).fold(self.level, Ord::max)
+    }
+
+    fn target_level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| match target.strip_prefix(&**prefix) {
+                Some(tail) => tail.is_empty() || tail.starts_with("::"),
+                None => false,
+            })
+            .map_or(self.level, |i| i.1)
+    }
+
+    /// Checks if the record has a `peer` field within `trace_peers`.
+    fn is_traced_peer(&self, record: &Record) -> bool {
+        if self.trace_peers.is_empty() {
+            return false;
+        }
+        let Some(peer) = record.key_values().get(Key::from_str("peer")) else {
+            return false;
+        };
+        // formatted on the stack, every trace record is checked
+        let mut buf = PeerBuf::default();
+        if write!(buf, "{}", peer).is_err() {
+            return false;
+        }
+        let peer = buf.as_str();
+        let ip = match peer.parse::<SocketAddrV4>() {
+            Ok(addr) => *addr.ip(),
+            Err(_) => match peer.parse::<Ipv4Addr>() {
+                Ok(ip) => ip,
+                Err(_) => return false,
+            },
+        };
+        self.trace_peers.iter().any(|i| i.contains(ip))
+    }
+
+    fn format_text(&self, record: &Record) -> String {
+        #[cfg(not(feature = "logtime"))]
+        return format!("{} - {}", record.level(), record.args());
+
+        #[cfg(feature = "logtime")]
+        {
+            let dt = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+            format!("[{}] {} - {}", dt, record.level(), record.args())
+        }
+    }
+
+    fn format_json(&self, record: &Record) -> String {
+        let mut obj = Map::new();
+
+        #[cfg(feature = "logtime")]
+        let dt = chrono::Local::now().to_rfc3339();
+        #[cfg(not(feature = "logtime"))]
+        let dt = utc_timestamp(SystemTime::now());
+        obj.insert("timestamp".into(), dt.into());
+
+        obj.insert("level".into(), record.level().as_str().into());
+        obj.insert("target".into(), record.target().into());
+        obj.insert("message".into(), record.args().to_string().into());
+
+        let mut fields = Fields(Map::new());
+        let _ = record.key_values().visit(&mut fields);
+        if !fields.0.is_empty() {
+            obj.insert("fields".into(), fields.0.into());
+        }
+
+        JsonValue::Object(obj).to_string()
+    }
+
+    fn write(&self, line: &str) {
+        match self.file {
+            Some(ref file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = file.write_line(line) {
+                    eprintln!("Failed to write log file: {}", e);
+                    eprintln!("{}", line);
+                }
             }
+            None if self.stderr => eprintln!("{}", line),
+            None => println!("{}", line),
+        }
+    }
+}
+
+/// Fixed-size buffer which fits the longest IPv4 socket address.
+#[derive(Default)]
+struct PeerBuf {
+    buf: [u8; 21],
+    len: usize,
+}
+
+impl PeerBuf {
+    fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for PeerBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        let dst = self.buf.get_mut(self.len..end).ok_or(fmt::Error)?;
+        dst.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Formats an RFC 3339 UTC timestamp without the `chrono` dependency.
+#[cfg(not(feature = "logtime"))]
+fn utc_timestamp(now: SystemTime) -> String {
+    let d = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, secs) = ((d.as_secs() / 86400) as i64, d.as_secs() % 86400);
+    // civil date from days since the epoch, proleptic Gregorian calendar
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        d.subsec_millis()
+    )
+}
+
+/// Collects structured key-values of a log record.
+struct Fields(Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => n.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.target_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) || self.is_traced_peer(record) {
+            let line = match self.format {
+                LogFormat::Text => self.format_text(record),
+                LogFormat::Json => self.format_json(record),
+            };
+            self.write(&line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(ref file) = self.file {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = file.flush();
         }
     }
+}
 
-    fn flush(&self) {}
+pub fn init(cfg: &LogConfig) -> io::Result<()> {
+    let file = match cfg.file {
+        Some(ref file) => Some(LogFile::open(file)?),
+        None => None,
+    };
+    install(Logger::new(cfg, file));
+    Ok(())
 }
 
-static LOGGER: Logger = Logger;
+/// Logs to stderr, client commands print their results to stdout.
+pub fn init_stderr(cfg: &LogConfig) {
+    let mut logger = Logger::new(cfg, None);
+    logger.stderr = true;
+    install(logger);
+}
 
-pub fn init(level_filter: LevelFilter) {
-    if let Err(e) = log::set_logger(&LOGGER) {
+fn install(logger: Logger) {
+    let max_level = logger.max_level();
+    if let Err(e) = log::set_boxed_logger(Box::new(logger)) {
         eprintln!("Failed to initialize logger: {}", e);
     }
-    log::set_max_level(level_filter);
+    log::set_max_level(max_level);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::Level;
+
+    fn logger(targets: &[(&str, LevelFilter)], trace_peers: &[&str]) -> Logger {
+        let cfg = LogConfig {
+            targets: targets.iter().map(|&(t, l)| (t.into(), l)).collect(),
+            trace_peers: trace_peers.iter().map(|s| s.parse().unwrap()).collect(),
+            ..LogConfig::default()
+        };
+        Logger::new(&cfg, None)
+    }
+
+    #[test]
+    fn target_level() {
+        let logger = logger(
+            &[
+                ("phantasma", LevelFilter::Info),
+                ("phantasma::filter", LevelFilter::Debug),
+            ],
+            &[],
+        );
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+        assert_eq!(logger.target_level("phantasma::filter"), LevelFilter::Debug);
+        assert_eq!(
+            logger.target_level("phantasma::filter::x"),
+            LevelFilter::Debug
+        );
+        assert_eq!(logger.target_level("phantasma::filters"), LevelFilter::Info);
+        assert_eq!(logger.target_level("phantasma"), LevelFilter::Info);
+        assert_eq!(logger.target_level("other"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn traced_peer() {
+        let logger = logger(&[], &["10.0.0.0/8"]);
+        assert_eq!(logger.max_level(), LevelFilter::Trace);
+
+        let traced = |peer: &str| {
+            let kvs = [("peer", Value::from(peer))];
+            let record = Record::builder()
+                .level(Level::Trace)
+                .key_values(&kvs)
+                .build();
+            logger.is_traced_peer(&record)
+        };
+        assert!(traced("10.1.2.3:27015"));
+        assert!(traced("10.1.2.3"));
+        assert!(!traced("11.1.2.3:27015"));
+        assert!(!traced("255.255.255.255:65535 and more"));
+        assert!(!logger.is_traced_peer(&Record::builder().level(Level::Trace).build()));
+    }
+
+    #[test]
+    fn collect_fields() {
+        let kvs = [
+            ("peer", Value::from("1.2.3.4:27015")),
+            ("count", Value::from(3u8)),
+        ];
+        let record = Record::builder()
+            .level(Level::Info)
+            .key_values(&kvs)
+            .build();
+
+        let mut fields = Fields(Map::new());
+        record.key_values().visit(&mut fields).unwrap();
+        assert_eq!(
+            JsonValue::Object(fields.0),
+            serde_json::json!({ "peer": "1.2.3.4:27015", "count": 3 })
+        );
+    }
+
+    #[test]
+    fn json_timestamp() {
+        let logger = Logger::new(
+            &LogConfig {
+                format: LogFormat::Json,
+                ..LogConfig::default()
+            },
+            None,
+        );
+        let record = Record::builder().level(Level::Info).build();
+        let line: JsonValue = serde_json::from_str(&logger.format_json(&record)).unwrap();
+        assert!(line["timestamp"].is_string());
+    }
+
+    #[cfg(not(feature = "logtime"))]
+    #[test]
+    fn utc_timestamps() {
+        use std::time::Duration;
+
+        let at = |secs, millis| {
+            let d = Duration::from_secs(secs) + Duration::from_millis(millis);
+            utc_timestamp(UNIX_EPOCH + d)
+        };
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(at(1_700_000_000, 250), "2023-11-14T22:13:20.250Z");
+    }
 }
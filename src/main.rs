@@ -1,16 +1,33 @@
+mod audit;
+mod bench;
+mod cidr;
 mod cli;
 mod client;
 mod config;
+mod dpmaster;
 mod filter;
+mod geoip;
+mod intern;
+mod log_file;
 mod logger;
 mod master_server;
+#[cfg(target_os = "linux")]
+mod mmsg;
 mod parser;
+mod query;
+mod register;
 mod server;
 mod server_info;
+mod server_table;
+mod stats;
+mod statsd;
 
-use log::error;
+use std::fmt::Display;
 
-use crate::config::Config;
+use log::{LevelFilter, error};
+
+use crate::cli::Command;
+use crate::config::{Config, LogConfig};
 
 fn main() {
     let cli = cli::parse().unwrap_or_else(|e| {
@@ -18,6 +35,15 @@ fn main() {
         std::process::exit(1);
     });
 
+    match cli.command {
+        Command::Server => {}
+        Command::Query(query) => return run_command(cli.log_level, || query::run(query)),
+        Command::Register(register) => {
+            return run_command(cli.log_level, || register::run(register));
+        }
+        Command::Bench(bench) => return run_command(cli.log_level, || bench::run(bench)),
+    }
+
     let mut cfg = match cli.config_path {
         Some(ref p) => match config::load(p.as_ref()) {
             Ok(config) => config,
@@ -41,10 +67,27 @@ fn main() {
         cfg.server.port = port;
     }
 
-    logger::init(cfg.log.level);
+    if let Err(e) = logger::init(&cfg.log) {
+        eprintln!("Failed to open log file: {}", e);
+        std::process::exit(1);
+    }
 
     if let Err(e) = master_server::run(cfg) {
         error!("{}", e);
         std::process::exit(1);
     }
 }
+
+/// Runs a client command logging to stderr.
+fn run_command<E: Display>(log_level: Option<LevelFilter>, f: impl FnOnce() -> Result<(), E>) {
+    let mut log = LogConfig::default();
+    if let Some(level) = log_level {
+        log.level = level;
+    }
+    logger::init_stderr(&log);
+
+    if let Err(e) = f() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,36 +1,88 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::prelude::*;
 use std::io::{self, Cursor};
-use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
-use std::ops::Deref;
-use std::time::Instant;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::panic;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use fastrand::Rng;
-use log::{error, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use thiserror::Error;
 
+use crate::audit::AuditLog;
+use crate::cidr;
 use crate::client::Packet;
-use crate::config::{self, Config};
+use crate::config::{self, Config, GeoipMode, Order, Overflow, PrivatePolicy, Protocol};
+use crate::dpmaster;
 use crate::filter::Filter;
-use crate::server::Server;
-use crate::server_info::Region;
+use crate::geoip::{self, GeoIp};
+#[cfg(target_os = "linux")]
+use crate::mmsg;
+use crate::server::{Family, Server};
+use crate::server_info::{Region, ServerInfo};
+use crate::server_table::{Entry, ExpiryQueue, ServerTable};
+use crate::stats::{Counter, Gauge, Stats};
+use crate::statsd;
 
 /// The maximum size of UDP packets.
-const MAX_PACKET_SIZE: usize = 512;
+pub const MAX_PACKET_SIZE: usize = 512;
 
-const CHALLENGE_RESPONSE_HEADER: &[u8] = b"\xff\xff\xff\xffs\n";
-const SERVER_LIST_HEADER: &[u8] = b"\xff\xff\xff\xfff\n";
+/// Size of receive buffers, dpmaster info responses are larger than Valve packets.
+pub const RECV_BUFFER_SIZE: usize = 2048;
 
-/// How many cleanup calls should be skipped before removing outdated servers.
-const SERVER_CLEANUP_MAX: usize = 100;
+pub const CHALLENGE_RESPONSE_HEADER: &[u8] = b"\xff\xff\xff\xffs\n";
+pub const SERVER_LIST_HEADER: &[u8] = b"\xff\xff\xff\xfff\n";
+/// Asks a server behind NAT to send a packet to the client address which follows.
+pub const CLIENT_ANNOUNCE_HEADER: &[u8] = b"\xff\xff\xff\xffc ";
 
-/// How many cleanup calls should be skipped before removing outdated challenges.
-const CHALLENGE_CLEANUP_MAX: usize = 100;
+/// Xash3D FWGS key extension after the list header: marker, key (LE u32), end.
+const XASH_KEY_MARKER: u8 = 0x7f;
+const XASH_KEY_END: u8 = 8;
+const XASH_KEY_SIZE: usize = 6;
+
+/// Maximum number of servers in one server list datagram, the header, key
+/// extension and `0.0.0.0:0` terminator take the rest. Only the last datagram
+/// of a list has less servers.
+pub const MAX_SERVERS_PER_PACKET: usize =
+    (MAX_PACKET_SIZE - SERVER_LIST_HEADER.len() - XASH_KEY_SIZE - 6) / 6;
+
+/// Maximum number of datagrams received by one `recvmmsg` call.
+#[cfg(target_os = "linux")]
+const BATCH_SIZE: usize = 32;
+
+/// How often outdated challenges and servers are removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the server memory gauge is sampled, it scans every index.
+const MEMORY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of NAT announces sent for one query.
+const MAX_ANNOUNCES_PER_QUERY: usize = 32;
+/// Maximum number of NAT announces sent for one client IP in an announce window.
+const MAX_ANNOUNCES_PER_CLIENT: usize = 128;
+/// Length of the announce window in seconds.
+const ANNOUNCE_WINDOW: u32 = 10;
+
+/// Length of the order epoch in seconds, lists are sorted by a snapshot of the
+/// server table taken at the start of each epoch.
+const ORDER_EPOCH: u32 = 60;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to bind server socket: {0}")]
     BindSocket(io::Error),
+    #[error("Failed to start StatsD emitter: {0}")]
+    Statsd(io::Error),
+    #[error("Failed to open audit log: {0}")]
+    Audit(io::Error),
+    #[error("Failed to open GeoIP database: {0}")]
+    Geoip(geoip::Error),
+    #[error("Pinned server {0} is rejected by the {1}")]
+    PinnedServer(SocketAddrV4, &'static str),
     #[error("Failed to decode packet: {0}")]
     ClientPacket(#[from] crate::client::Error),
     #[error("Missing challenge in ServerInfo")]
@@ -39,151 +91,769 @@ pub enum Error {
     Io(#[from] io::Error),
 }
 
-/// HashMap entry to keep tracking creation time.
-struct Entry<T> {
-    time: u32,
-    value: T,
+/// Challenges sent to servers which have not answered with `ServerAdd` yet.
+#[derive(Default)]
+struct Challenges {
+    map: HashMap<SocketAddrV4, Entry<u32>>,
+    expiry: ExpiryQueue<SocketAddrV4>,
 }
 
-impl<T> Entry<T> {
-    fn new(time: u32, value: T) -> Self {
-        Self { time, value }
-    }
-
-    fn is_valid(&self, now: u32, duration: u32) -> bool {
-        (now - self.time) < duration
+impl Challenges {
+    fn oldest(&mut self) -> Option<SocketAddrV4> {
+        let map = &self.map;
+        self.expiry
+            .oldest(|time, addr| map.get(addr).is_some_and(|i| i.time == time))
+            .map(|i| i.1)
     }
 }
 
-impl Entry<Server> {
-    fn matches(&self, addr: SocketAddrV4, region: Region, filter: &Filter) -> bool {
-        self.region == region && filter.matches(addr, self)
-    }
+/// NAT announces sent for each client IP in the current window.
+#[derive(Default)]
+struct Announces {
+    window: u32,
+    map: HashMap<Ipv4Addr, usize>,
 }
 
-impl<T> Deref for Entry<T> {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        &self.value
-    }
+/// Region and player count of servers at the start of an order epoch.
+///
+/// Pages of a query see the same players order and resume after a seed which
+/// has been removed since the previous page.
+#[derive(Default)]
+struct OrderSnapshot {
+    epoch: Option<u32>,
+    servers: HashMap<SocketAddrV4, (Region, u8)>,
 }
 
+/// Featured servers first, then region rank and order policy key.
+type OrderKey = (bool, usize, u64);
+
+/// State shared by all worker threads.
+///
+/// The server table is read-mostly, queries only take a read lock while
+/// collecting results so heartbeats are not blocked by sending server lists.
 struct MasterServer {
-    sock: UdpSocket,
-    challenges: HashMap<SocketAddrV4, Entry<u32>>,
-    servers: HashMap<SocketAddrV4, Entry<Server>>,
-    rng: Rng,
+    challenges: Mutex<Challenges>,
+    announces: Mutex<Announces>,
+    servers: RwLock<ServerTable>,
+    snapshot: RwLock<OrderSnapshot>,
+    stats: Arc<Stats>,
+    audit: Option<Mutex<AuditLog>>,
 
     start_time: Instant,
-    cleanup_challenges: usize,
-    cleanup_servers: usize,
     timeout: config::TimeoutConfig,
+    limits: config::LimitsConfig,
+    regions: config::RegionsConfig,
+    private: config::PrivateConfig,
+    order: config::OrderConfig,
+    games: Vec<config::GameConfig>,
+    /// Random key of the shuffle order.
+    shuffle_key: u64,
+    protocol: Protocol,
+    hide_lan: bool,
+    geoip: Option<GeoIp>,
+    geoip_mode: GeoipMode,
+}
+
+/// Receives and handles packets on a clone of the server socket.
+struct Worker {
+    sock: UdpSocket,
+    family: Family,
+    batch_io: bool,
+    rng: Rng,
+    master: Arc<MasterServer>,
 }
 
 impl MasterServer {
-    fn new(cfg: Config) -> Result<Self, Error> {
-        let addr = SocketAddr::new(cfg.server.ip, cfg.server.port);
-        info!("Listen address: {}", addr);
-        let sock = UdpSocket::bind(addr).map_err(Error::BindSocket)?;
+    fn new(mut cfg: Config) -> Result<Self, Error> {
+        let stats = Arc::new(Stats::default());
+        if let Some(statsd) = cfg.statsd {
+            statsd::spawn(statsd, stats.clone()).map_err(Error::Statsd)?;
+        }
+
+        let audit = match cfg.audit {
+            Some(ref audit) => Some(Mutex::new(AuditLog::open(audit).map_err(Error::Audit)?)),
+            None => None,
+        };
 
-        Ok(Self {
-            sock,
+        let (geoip, geoip_mode) = match cfg.geoip {
+            Some(ref geoip) => {
+                info!("GeoIP database: {} ({:?})", geoip.path, geoip.mode);
+                let db = GeoIp::open(&*geoip.path).map_err(Error::Geoip)?;
+                (Some(db), geoip.mode)
+            }
+            None => (None, GeoipMode::Trust),
+        };
+
+        let pinned = mem::take(&mut cfg.server.pinned);
+        let master = Self {
             start_time: Instant::now(),
             challenges: Default::default(),
+            announces: Default::default(),
             servers: Default::default(),
-            rng: Rng::new(),
-            cleanup_challenges: 0,
-            cleanup_servers: 0,
+            snapshot: Default::default(),
+            stats,
+            audit,
             timeout: cfg.server.timeout,
-        })
+            limits: cfg.server.limits,
+            regions: cfg.server.regions,
+            private: cfg.server.private,
+            order: cfg.server.order,
+            games: cfg.server.games,
+            shuffle_key: fastrand::u64(..),
+            protocol: cfg.server.protocol,
+            hide_lan: cfg.server.hide_lan,
+            geoip,
+            geoip_mode,
+        };
+        master.pin_servers(&pinned)?;
+        Ok(master)
+    }
+
+    /// Adds pinned servers, which must pass the checks of registered servers.
+    fn pin_servers(&self, pinned: &[config::PinnedServer]) -> Result<(), Error> {
+        let mut servers = self.servers_mut();
+        for pinned in pinned {
+            let info = pinned.server_info();
+            if self.is_rejected_private(*pinned.addr.ip()) {
+                return Err(Error::PinnedServer(pinned.addr, "private address policy"));
+            }
+            if !self.is_allowed_game(&info) {
+                return Err(Error::PinnedServer(pinned.addr, "game allowlist"));
+            }
+            info!("Pinned server: {} ({})", pinned.addr, pinned.gamedir);
+            let server = Server::new(&info, servers.strings_mut());
+            servers.pin(pinned.addr);
+            servers.insert(pinned.addr, Entry::new(0, server));
+        }
+        self.stats.set(Gauge::Servers, servers.len());
+        self.stats.set(Gauge::Strings, servers.strings().len());
+        Ok(())
+    }
+
+    fn now(&self) -> u32 {
+        self.start_time.elapsed().as_secs() as u32
+    }
+
+    fn challenges(&self) -> MutexGuard<'_, Challenges> {
+        self.challenges.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn servers(&self) -> RwLockReadGuard<'_, ServerTable> {
+        self.servers.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn servers_mut(&self) -> RwLockWriteGuard<'_, ServerTable> {
+        self.servers.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn snapshot(&self) -> RwLockReadGuard<'_, OrderSnapshot> {
+        self.snapshot.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn snapshot_mut(&self) -> RwLockWriteGuard<'_, OrderSnapshot> {
+        self.snapshot.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn audit(&self) -> Option<MutexGuard<'_, AuditLog>> {
+        self.audit
+            .as_ref()
+            .map(|i| i.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Returns `false` if the challenge table is full and the challenge is rejected.
+    fn add_challenge(&self, addr: SocketAddrV4, challenge: u32) -> bool {
+        let now = self.now();
+        let max = self.limits.max_challenges;
+        let mut challenges = self.challenges();
+        while max != 0 && challenges.map.len() >= max && !challenges.map.contains_key(&addr) {
+            let oldest = match self.limits.overflow {
+                Overflow::Evict => challenges.oldest(),
+                Overflow::Reject => None,
+            };
+            match oldest {
+                Some(old) => {
+                    challenges.map.remove(&old);
+                    self.stats.incr(Counter::ChallengeEvicted);
+                }
+                None => {
+                    debug!(peer:% = addr; "{}: Rejected challenge, table is full", addr);
+                    self.stats.incr(Counter::ChallengeRejected);
+                    return false;
+                }
+            }
+        }
+        challenges.map.insert(addr, Entry::new(now, challenge));
+        challenges.expiry.push(now, addr);
+        self.stats.set(Gauge::Challenges, challenges.map.len());
+        true
+    }
+
+    /// Removes the challenge of `addr` if it is valid and equal to `challenge`.
+    fn take_challenge(&self, addr: SocketAddrV4, challenge: u32) -> bool {
+        let now = self.now();
+        let mut challenges = self.challenges();
+        let entry = match challenges.map.get(&addr) {
+            Some(e) => e,
+            None => {
+                trace!(peer:% = addr; "{}: Challenge does not exists", addr);
+                return false;
+            }
+        };
+        if !entry.is_valid(now, self.timeout.challenge) {
+            return false;
+        }
+        if challenge != entry.value {
+            self.stats.incr(Counter::ChallengeInvalid);
+            warn!(
+                peer:% = addr;
+                "{}: Expected challenge {} but received {}",
+                addr, entry.value, challenge
+            );
+            return false;
+        }
+        challenges.map.remove(&addr);
+        self.stats.set(Gauge::Challenges, challenges.map.len());
+        true
+    }
+
+    fn remove_outdated_challenges(&self) {
+        let now = self.now();
+        let mut challenges = self.challenges();
+        let old = challenges.map.len();
+        while let Some((time, addr)) = challenges.expiry.pop_expired(now, self.timeout.challenge) {
+            if challenges.map.get(&addr).is_some_and(|i| i.time == time) {
+                challenges.map.remove(&addr);
+            }
+        }
+        let new = challenges.map.len();
+        if old != new {
+            trace!("Removed {} outdated challenges", old - new);
+        }
+        self.stats.set(Gauge::Challenges, new);
+    }
+
+    /// Returns how many of `n` NAT announces may be sent for a query of `client`.
+    fn take_announces(&self, client: Ipv4Addr, n: usize) -> usize {
+        let window = self.now() / ANNOUNCE_WINDOW;
+        let mut announces = self.announces.lock().unwrap_or_else(|e| e.into_inner());
+        if announces.window != window {
+            announces.window = window;
+            announces.map.clear();
+        }
+        let sent = announces.map.entry(client).or_default();
+        let n = n
+            .min(MAX_ANNOUNCES_PER_QUERY)
+            .min(MAX_ANNOUNCES_PER_CLIENT - *sent);
+        *sent += n;
+        n
+    }
+
+    /// Applies limits before adding a new server, returns `false` if it is rejected.
+    ///
+    /// Evicted servers are moved to `evicted` to be audited after the table is unlocked.
+    fn make_room(
+        &self,
+        servers: &mut ServerTable,
+        addr: SocketAddrV4,
+        evicted: &mut Vec<(SocketAddrV4, Entry<Server>)>,
+    ) -> bool {
+        let limits = &self.limits;
+        let ip = addr.ip();
+        let per_ip = limits.servers_per_ip(*ip);
+        // evicting would let a host replace its own servers forever
+        if per_ip != 0 && servers.count_ip(ip) >= per_ip {
+            debug!(
+                peer:% = addr;
+                "{}: Rejected GameServer, {} servers already registered from {}",
+                addr, per_ip, ip
+            );
+            self.stats.incr(Counter::ServerRejectedPerIp);
+            return false;
+        }
+        while limits.max_servers != 0 && servers.len() >= limits.max_servers {
+            let oldest = match limits.overflow {
+                Overflow::Evict => servers.oldest(),
+                Overflow::Reject => None,
+            };
+            match oldest {
+                Some(old) => self.evict_server(servers, old, evicted),
+                None => {
+                    debug!(peer:% = addr; "{}: Rejected GameServer, table is full", addr);
+                    self.stats.incr(Counter::ServerRejected);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn evict_server(
+        &self,
+        servers: &mut ServerTable,
+        addr: SocketAddrV4,
+        evicted: &mut Vec<(SocketAddrV4, Entry<Server>)>,
+    ) {
+        if let Some(old) = servers.remove(&addr) {
+            trace!(peer:% = addr; "{}: Evicted GameServer", addr);
+            self.stats.incr(Counter::ServerEvicted);
+            evicted.push((addr, old));
+        }
+    }
+
+    /// Returns the region of a server at `ip` according to the GeoIP mode.
+    fn region(&self, ip: Ipv4Addr, reported: Region) -> Region {
+        let geoip = match self.geoip {
+            Some(ref geoip) => geoip,
+            None => return reported,
+        };
+        match self.geoip_mode {
+            GeoipMode::Trust => reported,
+            GeoipMode::Fill if reported != Region::RestOfTheWorld => reported,
+            GeoipMode::Fill | GeoipMode::Override => geoip.lookup(ip).unwrap_or(reported),
+        }
     }
 
+    /// Returns `true` if the private address policy rejects servers at `ip`.
+    fn is_rejected_private(&self, ip: Ipv4Addr) -> bool {
+        self.private.policy == PrivatePolicy::Reject && cidr::private_range(ip).is_some()
+    }
+
+    /// Returns `true` if the game allowlist is empty or has an entry matching `info`.
+    fn is_allowed_game(&self, info: &ServerInfo<&str>) -> bool {
+        self.games.is_empty() || self.games.iter().any(|i| i.matches(info))
+    }
+
+    fn add_server(&self, addr: SocketAddrV4, family: Family, info: &ServerInfo<&str>) {
+        if self.is_rejected_private(*addr.ip()) {
+            debug!(peer:% = addr; "{}: Rejected server with private address", addr);
+            self.stats.incr(Counter::ServerRejectedPrivate);
+            return;
+        }
+        if !self.is_allowed_game(info) {
+            debug!(
+                peer:% = addr;
+                "{}: Rejected server of game {} ({})", addr, info.gamedir, info.product
+            );
+            self.stats.incr(Counter::ServerRejectedGame);
+            return;
+        }
+        let region = self.region(*addr.ip(), info.region);
+        let mut evicted = Vec::new();
+        let mut servers = self.servers_mut();
+        let added = servers.contains(&addr) || self.make_room(&mut servers, addr, &mut evicted);
+        let old = added.then(|| {
+            let mut server = Server::new(info, servers.strings_mut());
+            server.family = family;
+            server.region = region;
+            let old = servers.insert(addr, Entry::new(self.now(), server));
+            match old {
+                Some(_) => {
+                    trace!(peer:% = addr; "{}: Updated GameServer", addr);
+                    self.stats.incr(Counter::ServerUpdated);
+                }
+                None => {
+                    trace!(peer:% = addr; "{}: New GameServer", addr);
+                    self.stats.incr(Counter::ServerAdded);
+                    self.stats.set(Gauge::Servers, servers.len());
+                }
+            }
+            old
+        });
+        drop(servers);
+
+        // new servers keep their order until the next snapshot
+        if let Some(None) = old {
+            let mut snapshot = self.snapshot_mut();
+            snapshot.servers.insert(addr, (region, info.players));
+        }
+
+        // audit records are written without holding the table lock
+        if let Some(mut audit) = self.audit() {
+            for (addr, server) in &evicted {
+                audit.evict(*addr, server);
+            }
+            match old {
+                Some(Some(old)) if *old.map != *info.map || old.players != info.players => {
+                    audit.update(addr, info);
+                }
+                Some(None) => audit.add(addr, info),
+                Some(Some(_)) | None => {}
+            }
+        }
+    }
+
+    /// Remove requests are not authenticated, servers are removed when they expire.
+    fn reject_remove(&self, addr: SocketAddrV4) {
+        debug!(peer:% = addr; "{}: Ignored unauthenticated remove", addr);
+        if self.audit.is_none() {
+            return;
+        }
+        let server = self.servers().get(&addr).map(|i| i.value.clone());
+        if let Some(mut audit) = self.audit() {
+            audit.reject_remove(addr, server.as_ref());
+        }
+    }
+
+    fn remove_outdated_servers(&self) {
+        let now = self.now();
+        let mut servers = self.servers_mut();
+        let old = servers.len();
+        let removed = servers.expire(now, self.timeout.server);
+        let new = servers.len();
+        self.stats.set(Gauge::Strings, servers.strings().len());
+        drop(servers);
+        if let Some(mut audit) = self.audit() {
+            for (addr, server) in &removed {
+                audit.expire(*addr, server);
+            }
+        }
+        if old != new {
+            trace!("Removed {} outdated servers", old - new);
+        }
+        self.stats.set(Gauge::Servers, new);
+    }
+
+    fn update_memory(&self) {
+        let servers = self.servers();
+        let memory = servers.memory().checked_div(servers.len()).unwrap_or(0);
+        drop(servers);
+        self.stats.set(Gauge::ServerMemory, memory);
+    }
+
+    /// Takes a new order snapshot when an order epoch starts.
+    fn update_snapshot(&self) {
+        let epoch = self.now() / ORDER_EPOCH;
+        if self.snapshot().epoch == Some(epoch) {
+            return;
+        }
+        let servers = self.servers();
+        let snapshot = OrderSnapshot {
+            epoch: Some(epoch),
+            servers: servers
+                .iter()
+                .map(|(addr, entry)| (*addr, (entry.region, entry.players)))
+                .collect(),
+        };
+        drop(servers);
+        *self.snapshot_mut() = snapshot;
+    }
+
+    /// Returns `true` if the server is pinned or was updated recently.
+    fn is_listed(
+        &self,
+        servers: &ServerTable,
+        (addr, entry): (&SocketAddrV4, &Entry<Server>),
+        now: u32,
+    ) -> bool {
+        entry.is_valid(now, self.timeout.server) || servers.is_pinned(addr)
+    }
+
+    /// Returns `false` for LAN servers hidden from clients outside their network.
+    fn is_visible_lan(&self, addr: SocketAddrV4, server: &Server, client: Ipv4Addr) -> bool {
+        !self.hide_lan || !server.is_lan() || cidr::same_network(*addr.ip(), client)
+    }
+
+    /// Returns `true` if listed addresses may repeat, rewritten private servers
+    /// share public addresses.
+    fn may_repeat(&self) -> bool {
+        self.private.policy == PrivatePolicy::Rewrite
+    }
+
+    /// Returns addresses of valid dpmaster servers matching `query` listed to `client`.
+    fn query_dpmaster(&self, client: Ipv4Addr, query: &dpmaster::Query) -> Vec<SocketAddrV4> {
+        let now = self.now();
+        let servers = self.servers();
+        let mut list: Vec<_> = servers
+            .by_gamedir(query.gamename)
+            .filter(|i| self.is_listed(&servers, *i, now) && query.matches(i.1))
+            .filter_map(|i| self.private.listed_addr(*i.0, client))
+            .collect();
+        drop(servers);
+        if self.may_repeat() {
+            let mut seen = HashSet::new();
+            list.retain(|i| seen.insert(*i));
+        }
+        list
+    }
+
+    /// Returns the sort key of a server at `addr` with `players` in lists sent to
+    /// `client`, `rank` is the position of the server region in the list.
+    fn order_key(
+        &self,
+        epoch: Option<u32>,
+        client: Ipv4Addr,
+        addr: SocketAddrV4,
+        rank: usize,
+        players: u8,
+    ) -> OrderKey {
+        let key = match self.order.policy {
+            Order::None => 0,
+            Order::Players => u64::from(u8::MAX - players),
+            Order::Shuffle => {
+                let mut hasher = DefaultHasher::new();
+                (self.shuffle_key, epoch, client, addr).hash(&mut hasher);
+                hasher.finish()
+            }
+        };
+        (!self.order.is_featured(*addr.ip()), rank, key)
+    }
+
+    /// Returns addresses of valid servers in `region` matching `filter` listed to
+    /// `client`, paired with the real address of servers behind NAT.
+    ///
+    /// Neighbour regions follow if the region has less than `min_results` servers.
+    /// Featured servers come first, then the region and the order policy decide.
+    /// The list continues after the sort key of `seed`, which is looked up in the
+    /// order snapshot if the seed is not listed anymore.
+    fn query(
+        &self,
+        client: SocketAddrV4,
+        region: Region,
+        seed: Option<SocketAddrV4>,
+        filter: &Filter,
+    ) -> Vec<(SocketAddrV4, Option<SocketAddrV4>)> {
+        let now = self.now();
+        let client = *client.ip();
+        let snapshot = self.snapshot();
+        let players = |addr: SocketAddrV4, server: &Server| {
+            snapshot.servers.get(&addr).map_or(server.players, |i| i.1)
+        };
+        let servers = self.servers();
+        let add = |list: &mut Vec<_>, region, done: &[Region]| {
+            let rank = done.len();
+            list.extend(
+                servers
+                    .query(region, filter)
+                    .filter(|i| self.is_listed(&servers, *i, now))
+                    .filter(|i| !done.contains(&i.1.region))
+                    .filter(|i| self.is_visible_lan(*i.0, i.1, client))
+                    .filter_map(|(&addr, entry)| {
+                        let listed = self.private.listed_addr(addr, client)?;
+                        let players = players(addr, entry);
+                        let key = self.order_key(snapshot.epoch, client, addr, rank, players);
+                        Some((key, listed, entry.is_nat().then_some(addr)))
+                    }),
+            )
+        };
+
+        let mut list = Vec::new();
+        let mut done = vec![];
+        add(&mut list, region, &done);
+        done.push(region);
+        for &next in self.regions.neighbours(region) {
+            if list.len() >= self.regions.min_results {
+                break;
+            }
+            if !done.contains(&next) {
+                add(&mut list, next, &done);
+                done.push(next);
+            }
+        }
+        drop(servers);
+
+        // the address breaks ties so every page sees the same order
+        list.sort_unstable_by_key(|i| (i.0, i.1));
+        if self.may_repeat() {
+            let mut seen = HashSet::new();
+            list.retain(|i| seen.insert(i.1));
+        }
+
+        let seed_key = seed.and_then(|seed| match list.iter().find(|i| i.1 == seed) {
+            Some(i) => Some((i.0, seed)),
+            None => {
+                // servers of regions missing from this list would follow it
+                let &(region, players) = snapshot.servers.get(&seed)?;
+                let rank = done
+                    .iter()
+                    .position(|&i| i == region || i == Region::RestOfTheWorld)
+                    .unwrap_or(done.len());
+                let key = self.order_key(snapshot.epoch, client, seed, rank, players);
+                Some((key, seed))
+            }
+        });
+        let start = seed_key.map_or(0, |seed| list.partition_point(|i| (i.0, i.1) <= seed));
+        list.drain(start..).map(|i| (i.1, i.2)).collect()
+    }
+}
+
+impl Worker {
     fn run(&mut self) -> Result<(), Error> {
-        let mut buf = [0; MAX_PACKET_SIZE];
+        #[cfg(target_os = "linux")]
+        if self.batch_io {
+            return self.run_batched();
+        }
+
+        let mut buf = [0; RECV_BUFFER_SIZE];
         loop {
             let (n, from) = self.sock.recv_from(&mut buf)?;
-            let from = match from {
-                SocketAddr::V4(a) => a,
-                _ => {
-                    warn!("{}: Received message from IPv6, unimplemented", from);
-                    continue;
+            self.handle(from, &buf[..n]);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn run_batched(&mut self) -> Result<(), Error> {
+        let mut batch = mmsg::RecvBatch::new(BATCH_SIZE);
+        loop {
+            let n = batch.recv(&self.sock)?;
+            for i in 0..n {
+                match batch.get(i) {
+                    (Some(from), data) => self.handle(from, data),
+                    (None, _) => warn!("Received message from unknown address family"),
                 }
-            };
+            }
+        }
+    }
 
-            if let Err(e) = self.handle_packet(from, &buf[..n]) {
-                error!("{}: {}", from, e);
+    fn handle(&mut self, from: SocketAddr, s: &[u8]) {
+        let from = match from {
+            SocketAddr::V4(a) => a,
+            _ => {
+                warn!(peer:% = from; "{}: Received message from IPv6, unimplemented", from);
+                return;
             }
+        };
+
+        let res = match self.family {
+            Family::Valve => self.handle_packet(from, s),
+            Family::Dpmaster => self.handle_dpmaster(from, s),
+        };
+        if let Err(e) = res {
+            error!(peer:% = from; "{}: {}", from, e);
         }
     }
 
+    fn handle_dpmaster(&mut self, from: SocketAddrV4, s: &[u8]) -> Result<(), Error> {
+        let stats = &self.master.stats;
+        let packet = match dpmaster::Packet::decode(s) {
+            Ok(p) => p,
+            Err(_) => {
+                trace!(peer:% = from; "{}: Failed to decode {:?}", from, s);
+                stats.incr(Counter::PacketInvalid);
+                return Ok(());
+            }
+        };
+
+        trace!(peer:% = from, packet = packet.kind(); "{}: recv {:?}", from, packet);
+
+        stats.incr(match packet {
+            dpmaster::Packet::Heartbeat(_) => Counter::PacketDpHeartbeat,
+            dpmaster::Packet::InfoResponse(_) => Counter::PacketDpInfoResponse,
+            dpmaster::Packet::GetServers(_) | dpmaster::Packet::GetServersExt(_) => {
+                Counter::PacketDpGetServers
+            }
+        });
+
+        match packet {
+            dpmaster::Packet::Heartbeat(dpmaster::FLATLINE) => self.master.reject_remove(from),
+            dpmaster::Packet::Heartbeat(_) => {
+                let challenge = self.rng.u32(..);
+                if !self.master.add_challenge(from, challenge) {
+                    return Ok(());
+                }
+                trace!(peer:% = from; "{}: New challenge {}", from, challenge);
+                self.sock
+                    .send_to(&dpmaster::encode_getinfo(challenge), from)?;
+            }
+            dpmaster::Packet::InfoResponse(info) => {
+                let challenge = match dpmaster::decode_challenge(info.challenge) {
+                    Some(c) => c,
+                    None => {
+                        debug!(peer:% = from; "{}: Invalid challenge in infoResponse", from);
+                        stats.incr(Counter::ChallengeInvalid);
+                        return Ok(());
+                    }
+                };
+                if self.master.take_challenge(from, challenge) {
+                    self.master
+                        .add_server(from, Family::Dpmaster, &info.server_info());
+                }
+            }
+            dpmaster::Packet::GetServers(ref query)
+            | dpmaster::Packet::GetServersExt(ref query) => {
+                let ext = matches!(packet, dpmaster::Packet::GetServersExt(_));
+                let list = match query.ipv4 {
+                    true => self.master.query_dpmaster(*from.ip(), query),
+                    false => Vec::new(),
+                };
+                self.master
+                    .stats
+                    .add(Counter::QueryResults, list.len() as u64);
+                let packets = dpmaster::encode_servers(ext, list);
+                let packets: Vec<_> = packets.iter().map(|i| &i[..]).collect();
+                self.send_packets(from, &packets)?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn handle_packet(&mut self, from: SocketAddrV4, s: &[u8]) -> Result<(), Error> {
-        let packet = match Packet::decode(s) {
+        let stats = &self.master.stats;
+        let packet = match self.master.protocol {
+            Protocol::Valve => Packet::decode(s),
+            Protocol::Xash => Packet::decode_xash(s),
+        };
+        let packet = match packet {
             Ok(p) => p,
             Err(_) => {
-                trace!("{}: Failed to decode {:?}", from, s);
+                trace!(peer:% = from; "{}: Failed to decode {:?}", from, s);
+                stats.incr(Counter::PacketInvalid);
                 return Ok(());
             }
         };
 
-        trace!("{}: recv {:?}", from, packet);
+        trace!(peer:% = from, packet = packet.kind(); "{}: recv {:?}", from, packet);
+
+        stats.incr(match packet {
+            Packet::Challenge(_) => Counter::PacketChallenge,
+            Packet::ServerAdd(..) => Counter::PacketServerAdd,
+            Packet::ServerRemove => Counter::PacketServerRemove,
+            Packet::QueryServers(..) | Packet::XashQueryServers(..) => Counter::PacketQueryServers,
+            Packet::ServerInfo => Counter::PacketServerInfo,
+        });
 
         match packet {
             Packet::Challenge(server_challenge) => {
-                let challenge = self.add_challenge(from);
-                trace!("{}: New challenge {}", from, challenge);
+                let challenge = self.rng.u32(..);
+                if !self.master.add_challenge(from, challenge) {
+                    return Ok(());
+                }
+                trace!(peer:% = from; "{}: New challenge {}", from, challenge);
                 self.send_challenge_response(from, challenge, server_challenge)?;
-                self.remove_outdated_challenges();
             }
             Packet::ServerAdd(challenge, info) => {
                 let challenge = match challenge {
                     Some(c) => c,
                     None => return Err(Error::MissingChallenge),
                 };
-                let entry = match self.challenges.get(&from) {
-                    Some(e) => e,
-                    None => {
-                        trace!("{}: Challenge does not exists", from);
+                if self.master.take_challenge(from, challenge) {
+                    self.master.add_server(from, Family::Valve, &info);
+                }
+            }
+            Packet::ServerRemove => self.master.reject_remove(from),
+            Packet::QueryServers(region, seed, filter) => {
+                let filter = match Filter::from_bytes(&filter) {
+                    Ok(f) => f,
+                    _ => {
+                        warn!(peer:% = from; "{}: Invalid filter: {:?}", from, filter);
                         return Ok(());
                     }
                 };
-                if !entry.is_valid(self.now(), self.timeout.challenge) {
-                    return Ok(());
-                }
-                if challenge != entry.value {
-                    warn!(
-                        "{}: Expected challenge {} but received {}",
-                        from, entry.value, challenge
-                    );
-                    return Ok(());
-                }
-                if self.challenges.remove(&from).is_some() {
-                    self.add_server(from, Server::new(&info));
-                }
-                self.remove_outdated_servers();
+                self.send_query(from, region, seed, &filter, None)?;
             }
-            Packet::ServerRemove => { /* ignore */ }
-            Packet::QueryServers(region, filter) => {
-                let filter = match Filter::from_bytes(&filter) {
+            Packet::XashQueryServers(region, seed, filter) => {
+                let filter = match Filter::from_bytes_xash(&filter) {
                     Ok(f) => f,
                     _ => {
-                        warn!("{}: Invalid filter: {:?}", from, filter);
+                        warn!(peer:% = from; "{}: Invalid filter: {:?}", from, filter);
                         return Ok(());
                     }
                 };
-
-                let now = self.now();
-                let iter = self
-                    .servers
-                    .iter()
-                    .filter(|i| i.1.is_valid(now, self.timeout.server))
-                    .filter(|i| i.1.matches(*i.0, region, &filter))
-                    .map(|i| i.0);
-                self.send_server_list(from, iter)?;
+                self.send_query(from, region, seed, &filter, filter.key)?;
             }
             Packet::ServerInfo => {
                 let mut buf = [0; MAX_PACKET_SIZE];
-                let mut cur = Cursor::new(&mut buf[..]);
+                let cur = Cursor::new(&mut buf[..]);
                 let n = cur.position() as usize;
                 self.sock.send_to(&buf[..n], from)?;
             }
@@ -192,54 +862,21 @@ impl MasterServer {
         Ok(())
     }
 
-    fn now(&self) -> u32 {
-        self.start_time.elapsed().as_secs() as u32
-    }
-
-    fn add_challenge(&mut self, addr: SocketAddrV4) -> u32 {
-        let x = self.rng.u32(..);
-        let entry = Entry::new(self.now(), x);
-        self.challenges.insert(addr, entry);
-        x
-    }
-
-    fn remove_outdated_challenges(&mut self) {
-        if self.cleanup_challenges < CHALLENGE_CLEANUP_MAX {
-            self.cleanup_challenges += 1;
-            return;
-        }
-        let now = self.now();
-        let old = self.challenges.len();
-        self.challenges
-            .retain(|_, v| v.is_valid(now, self.timeout.challenge));
-        let new = self.challenges.len();
-        if old != new {
-            trace!("Removed {} outdated challenges", old - new);
-        }
-        self.cleanup_challenges = 0;
-    }
-
-    fn add_server(&mut self, addr: SocketAddrV4, server: Server) {
-        match self.servers.insert(addr, Entry::new(self.now(), server)) {
-            Some(_) => trace!("{}: Updated GameServer", addr),
-            None => trace!("{}: New GameServer", addr),
-        }
-    }
+    /// Sends the servers matching `filter`, `key` is echoed to Xash3D FWGS clients.
+    fn send_query(
+        &self,
+        from: SocketAddrV4,
+        region: Region,
+        seed: Option<SocketAddrV4>,
+        filter: &Filter,
+        key: Option<u32>,
+    ) -> Result<(), Error> {
+        let list = self.master.query(from, region, seed, filter);
+        self.send_server_list(from, key, list.iter().map(|i| &i.0))?;
 
-    fn remove_outdated_servers(&mut self) {
-        if self.cleanup_servers < SERVER_CLEANUP_MAX {
-            self.cleanup_servers += 1;
-            return;
-        }
-        let now = self.now();
-        let old = self.servers.len();
-        self.servers
-            .retain(|_, v| v.is_valid(now, self.timeout.server));
-        let new = self.servers.len();
-        if old != new {
-            trace!("Removed {} outdated servers", old - new);
-        }
-        self.cleanup_servers = 0;
+        // listed servers behind NAT punch a hole to the client
+        self.announce_client(from, list.iter().filter_map(|i| i.1));
+        Ok(())
     }
 
     fn send_challenge_response<A: ToSocketAddrs>(
@@ -262,44 +899,385 @@ impl MasterServer {
         Ok(())
     }
 
-    fn send_server_list<'a, A, I>(&self, to: A, mut iter: I) -> Result<(), io::Error>
+    /// Sends the client address to servers behind NAT within the announce limits.
+    fn announce_client<I>(&self, client: SocketAddrV4, servers: I)
     where
-        A: ToSocketAddrs,
-        I: Iterator<Item = &'a SocketAddrV4>,
+        I: Iterator<Item = SocketAddrV4> + Clone,
     {
+        let total = servers.clone().count();
+        let n = match total {
+            0 => return,
+            _ => self.master.take_announces(*client.ip(), total),
+        };
+        if n < total {
+            debug!(
+                peer:% = client;
+                "{}: Limited NAT announces to {} of {}", client, n, total
+            );
+        }
+        for addr in servers.take(n) {
+            if let Err(e) = self.send_client_announce(addr, client) {
+                debug!(peer:% = addr; "{}: Failed to send client announce: {}", addr, e);
+            }
+        }
+    }
+
+    fn send_client_announce(&self, to: SocketAddrV4, client: SocketAddrV4) -> io::Result<()> {
         let mut buf = [0; MAX_PACKET_SIZE];
+        let mut cur = Cursor::new(&mut buf[..]);
+
+        cur.write_all(CLIENT_ANNOUNCE_HEADER)?;
+        write!(cur, "{}", client)?;
+
+        let n = cur.position() as usize;
+        self.sock.send_to(&buf[..n], to)?;
+        self.master.stats.incr(Counter::NatAnnounce);
+        Ok(())
+    }
+
+    fn send_server_list<'a, I>(
+        &self,
+        to: SocketAddrV4,
+        key: Option<u32>,
+        mut iter: I,
+    ) -> Result<(), io::Error>
+    where
+        I: Iterator<Item = &'a SocketAddrV4>,
+    {
+        let mut buf = Vec::with_capacity(MAX_PACKET_SIZE);
+        let mut ends = Vec::new();
         let mut done = false;
         while !done {
-            let mut cur = Cursor::new(&mut buf[..]);
-            cur.write_all(SERVER_LIST_HEADER)?;
+            buf.extend_from_slice(SERVER_LIST_HEADER);
+            if let Some(key) = key {
+                buf.push(XASH_KEY_MARKER);
+                buf.extend_from_slice(&key.to_le_bytes());
+                buf.push(XASH_KEY_END);
+            }
 
-            loop {
+            for _ in 0..MAX_SERVERS_PER_PACKET {
                 match iter.next() {
                     Some(i) => {
-                        cur.write_all(&i.ip().octets()[..])?;
-                        cur.write_all(&i.port().to_be_bytes())?;
+                        buf.extend_from_slice(&i.ip().octets()[..]);
+                        buf.extend_from_slice(&i.port().to_be_bytes());
+                        self.master.stats.incr(Counter::QueryResults);
                     }
                     None => {
                         done = true;
                         break;
                     }
                 }
-
-                if (cur.position() as usize) > (MAX_PACKET_SIZE - 12) {
-                    break;
-                }
             }
 
             // terminate list
-            cur.write_all(&[0; 6][..])?;
+            buf.extend_from_slice(&[0; 6][..]);
+            ends.push(buf.len());
+        }
+
+        let mut start = 0;
+        let packets: Vec<_> = ends
+            .into_iter()
+            .map(|end| &buf[mem::replace(&mut start, end)..end])
+            .collect();
+        self.send_packets(to, &packets)
+    }
 
-            let n = cur.position() as usize;
-            self.sock.send_to(&buf[..n], &to)?;
+    fn send_packets(&self, to: SocketAddrV4, packets: &[&[u8]]) -> Result<(), io::Error> {
+        #[cfg(target_os = "linux")]
+        if self.batch_io {
+            return mmsg::send_to(&self.sock, to, packets);
+        }
+
+        for packet in packets {
+            self.sock.send_to(packet, to)?;
         }
         Ok(())
     }
 }
 
 pub fn run(cfg: Config) -> Result<(), Error> {
-    MasterServer::new(cfg)?.run()
+    let addr = SocketAddr::new(cfg.server.ip, cfg.server.port);
+    info!("Listen address: {}", addr);
+    info!("Protocol: {:?}", cfg.server.protocol);
+    let sock = UdpSocket::bind(addr).map_err(Error::BindSocket)?;
+    let mut listeners = vec![(sock, Family::Valve)];
+
+    if let Some(dpmaster) = &cfg.dpmaster {
+        let addr = SocketAddr::new(dpmaster.ip, dpmaster.port);
+        info!("Listen address (dpmaster): {}", addr);
+        let sock = UdpSocket::bind(addr).map_err(Error::BindSocket)?;
+        listeners.push((sock, Family::Dpmaster));
+    }
+
+    let threads = match cfg.server.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    info!("Worker threads: {}", threads);
+
+    let batch_io = cfg.server.batch_io;
+    if batch_io && cfg!(not(target_os = "linux")) {
+        warn!("Batched socket I/O is only supported on Linux");
+    }
+
+    let master = Arc::new(MasterServer::new(cfg)?);
+
+    // workers share one socket, the kernel wakes a single waiting thread per datagram
+    let mut workers = Vec::with_capacity(threads * listeners.len());
+    for (sock, family) in &listeners {
+        for i in 0..threads {
+            let mut worker = Worker {
+                sock: sock.try_clone()?,
+                family: *family,
+                batch_io,
+                rng: Rng::new(),
+                master: master.clone(),
+            };
+            let name = match family {
+                Family::Valve => format!("worker-{}", i),
+                Family::Dpmaster => format!("dpmaster-{}", i),
+            };
+            let handle = thread::Builder::new()
+                .name(name)
+                .spawn(move || worker.run())?;
+            workers.push(handle);
+        }
+    }
+
+    master.update_memory();
+    let mut memory_time = Instant::now();
+    loop {
+        thread::sleep(CLEANUP_INTERVAL);
+        master.remove_outdated_challenges();
+        master.remove_outdated_servers();
+        master.update_snapshot();
+
+        if memory_time.elapsed() >= MEMORY_INTERVAL {
+            master.update_memory();
+            memory_time = Instant::now();
+        }
+
+        if let Some(i) = workers.iter().position(|i| i.is_finished()) {
+            return match workers.swap_remove(i).join() {
+                Ok(res) => res,
+                Err(e) => panic::resume_unwind(e),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master(cfg: Config) -> Arc<MasterServer> {
+        Arc::new(MasterServer::new(cfg).unwrap())
+    }
+
+    fn add(master: &MasterServer, addr: SocketAddrV4, info: &[u8]) {
+        let (_, info, _) = ServerInfo::<&str>::from_bytes(info).unwrap();
+        master.add_server(addr, Family::Valve, &info);
+    }
+
+    fn bind() -> (UdpSocket, SocketAddrV4) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        match sock.local_addr().unwrap() {
+            SocketAddr::V4(addr) => (sock, addr),
+            SocketAddr::V6(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn nat_announce() {
+        let master = master(Config::default());
+        let (nat, nat_addr) = bind();
+        add(&master, nat_addr, b"\\gamedir\\valve\\nat\\1");
+
+        let (client, client_addr) = bind();
+        let mut worker = Worker {
+            sock: bind().0,
+            family: Family::Valve,
+            batch_io: false,
+            rng: Rng::new(),
+            master,
+        };
+        let mut buf = [0; MAX_PACKET_SIZE];
+
+        // announced to every client it is listed to
+        let expected = [CLIENT_ANNOUNCE_HEADER, client_addr.to_string().as_bytes()].concat();
+        for filter in [&b"\\gamedir\\valve"[..], b"\\nat\\1"] {
+            let query = [b"1\xff0.0.0.0:0\0", filter, b"\0"].concat();
+            worker.handle(client_addr.into(), &query);
+            let n = client.recv(&mut buf).unwrap();
+            assert_eq!(n, SERVER_LIST_HEADER.len() + 12);
+            let n = nat.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], expected);
+        }
+
+        // but not if it is filtered out
+        worker.handle(client_addr.into(), b"1\xff0.0.0.0:0\0\\nat\\0\0");
+        let n = client.recv(&mut buf).unwrap();
+        assert_eq!(n, SERVER_LIST_HEADER.len() + 6);
+        assert!(nat.recv(&mut buf).is_err());
+    }
+
+    #[test]
+    fn per_ip_limit() {
+        let mut cfg = Config::default();
+        cfg.server.limits.max_servers_per_ip = 2;
+        let master = master(cfg);
+        let addr = |port| SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 1), port);
+        for port in 1..=3 {
+            add(&master, addr(port), b"\\gamedir\\valve");
+        }
+        // the limit rejects new servers and never evicts registered ones
+        let servers = master.servers();
+        assert!(servers.contains(&addr(1)) && servers.contains(&addr(2)));
+        assert!(!servers.contains(&addr(3)));
+        drop(servers);
+        add(&master, addr(2), b"\\gamedir\\cstrike");
+        assert_eq!(master.servers().len(), 2);
+    }
+
+    #[test]
+    fn pinned_servers() {
+        let cfg = |extra: &str| -> Config {
+            let pinned = r#"
+                [[server.pinned]]
+                addr = "10.0.0.1:27015"
+                gamedir = "cstrike"
+                map = "de_dust2"
+            "#;
+            toml::from_str(&format!("{}{}", pinned, extra)).unwrap()
+        };
+        let master = master(cfg(""));
+        assert_eq!(master.stats.get(Gauge::Servers), 1);
+        assert_eq!(master.stats.get(Gauge::Strings), 3);
+
+        let reject = "[server.private]\npolicy = \"reject\"\n";
+        assert!(matches!(
+            MasterServer::new(cfg(reject)),
+            Err(Error::PinnedServer(_, _))
+        ));
+        let games = "[[server.games]]\ngamedir = \"valve\"\n";
+        assert!(matches!(
+            MasterServer::new(cfg(games)),
+            Err(Error::PinnedServer(_, _))
+        ));
+    }
+
+    #[test]
+    fn rewritten_duplicates() {
+        let cfg = toml::from_str(
+            r#"
+            [server.private]
+            policy = "rewrite"
+            [server.private.rewrite]
+            "192.168.0.0/16" = "203.0.113.1"
+            "#,
+        )
+        .unwrap();
+        let master = master(cfg);
+        let addr = |s: &str| s.parse::<SocketAddrV4>().unwrap();
+        add(&master, addr("192.168.1.5:27015"), b"\\gamedir\\valve");
+        add(&master, addr("192.168.1.6:27015"), b"\\gamedir\\valve");
+        add(&master, addr("203.0.113.1:27015"), b"\\gamedir\\valve");
+        add(&master, addr("192.168.1.6:27016"), b"\\gamedir\\valve");
+
+        let client = addr("198.51.100.7:27005");
+        let filter = Filter::default();
+        let list = master.query(client, Region::RestOfTheWorld, None, &filter);
+        let list: Vec<_> = list.iter().map(|i| i.0.to_string()).collect();
+        assert_eq!(list, ["203.0.113.1:27015", "203.0.113.1:27016"]);
+
+        let lan = addr("192.168.1.7:27005");
+        let list = master.query(lan, Region::RestOfTheWorld, None, &filter);
+        assert_eq!(list.len(), 4);
+    }
+
+    fn addrs(list: &[(SocketAddrV4, Option<SocketAddrV4>)]) -> Vec<SocketAddrV4> {
+        list.iter().map(|i| i.0).collect()
+    }
+
+    fn order_master(order: &str) -> Arc<MasterServer> {
+        let cfg = toml::from_str(&format!("[server.order]\n{}", order)).unwrap();
+        let master = master(cfg);
+        for i in 1..=20u8 {
+            let addr = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, i), 27015);
+            let info = format!("\\gamedir\\valve\\players\\{}\\max\\32", i % 7);
+            add(&master, addr, info.as_bytes());
+        }
+        master
+    }
+
+    #[test]
+    fn featured_first() {
+        let master = order_master("featured = [\"198.51.100.7\", \"198.51.100.16/30\"]\n");
+        let client = "203.0.113.1:27005".parse().unwrap();
+        let list = addrs(&master.query(client, Region::RestOfTheWorld, None, &Filter::default()));
+        let ip = |i: &SocketAddrV4| i.ip().octets()[3];
+        let list: Vec<_> = list.iter().map(ip).collect();
+        assert_eq!(list[..5], [7, 16, 17, 18, 19]);
+        assert!(list[5..].is_sorted());
+    }
+
+    #[test]
+    fn shuffle_pages() {
+        let master = order_master("policy = \"shuffle\"\n");
+        let query = |client: &str, seed| {
+            let client = client.parse().unwrap();
+            addrs(&master.query(client, Region::RestOfTheWorld, seed, &Filter::default()))
+        };
+        let list = query("203.0.113.1:27005", None);
+        assert_eq!(list.len(), 20);
+        assert!(!list.is_sorted());
+        // the order depends on the client IP only
+        assert_eq!(query("203.0.113.1:27006", None), list);
+        assert_eq!(query("203.0.113.1:27005", Some(list[9])), list[10..]);
+
+        // and changes with the order epoch
+        master.snapshot_mut().epoch = Some(1);
+        let next = query("203.0.113.1:27005", None);
+        assert_ne!(next, list);
+        assert_eq!(query("203.0.113.1:27005", Some(next[9])), next[10..]);
+    }
+
+    #[test]
+    fn players_pages() {
+        let master = order_master("policy = \"players\"\n");
+        master.update_snapshot();
+        let client = "203.0.113.1:27005".parse().unwrap();
+        let query =
+            |seed| addrs(&master.query(client, Region::RestOfTheWorld, seed, &Filter::default()));
+        let list = query(None);
+        let players = |addr: &SocketAddrV4| addr.ip().octets()[3] % 7;
+        assert!(list.windows(2).all(|i| players(&i[0]) >= players(&i[1])));
+
+        // player counts change but the order is kept until the next snapshot
+        add(&master, list[19], b"\\gamedir\\valve\\players\\30\\max\\32");
+        assert_eq!(query(None), list);
+        assert_eq!(query(Some(list[4])), list[5..]);
+
+        // resume after the sort key of a removed seed
+        master.servers_mut().remove(&list[9]);
+        assert_eq!(query(Some(list[9])), list[10..]);
+        let unknown = "192.0.2.1:27015".parse().unwrap();
+        assert_eq!(query(Some(unknown)).len(), 19);
+    }
+
+    #[test]
+    fn announce_limits() {
+        let master = master(Config::default());
+        let client = Ipv4Addr::new(203, 0, 113, 1);
+        assert_eq!(master.take_announces(client, 1000), MAX_ANNOUNCES_PER_QUERY);
+        let mut sent = MAX_ANNOUNCES_PER_QUERY;
+        while sent < MAX_ANNOUNCES_PER_CLIENT {
+            sent += master.take_announces(client, 1000);
+        }
+        assert_eq!(sent, MAX_ANNOUNCES_PER_CLIENT);
+        assert_eq!(master.take_announces(client, 1), 0);
+        assert_eq!(master.take_announces(Ipv4Addr::new(203, 0, 113, 2), 1), 1);
+    }
 }
@@ -0,0 +1,175 @@
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::ptr;
+
+use crate::master_server::RECV_BUFFER_SIZE;
+
+/// Receive buffers for `recvmmsg`.
+pub struct RecvBatch {
+    bufs: Vec<[u8; RECV_BUFFER_SIZE]>,
+    addrs: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>,
+}
+
+impl RecvBatch {
+    pub fn new(size: usize) -> Self {
+        Self {
+            bufs: vec![[0; RECV_BUFFER_SIZE]; size],
+            // SAFETY: all-zero is a valid sockaddr_storage
+            addrs: vec![unsafe { mem::zeroed() }; size],
+            iovecs: Vec::with_capacity(size),
+            msgs: Vec::with_capacity(size),
+        }
+    }
+
+    /// Blocks until at least one datagram is available and receives up to the
+    /// batch size without waiting for more. Returns the number of datagrams.
+    pub fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        self.iovecs.clear();
+        self.msgs.clear();
+        for buf in &mut self.bufs {
+            self.iovecs.push(libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            });
+        }
+        for (addr, iov) in self.addrs.iter_mut().zip(&mut self.iovecs) {
+            // SAFETY: all-zero is a valid msghdr
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = ptr::from_mut(addr).cast();
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = iov;
+            hdr.msg_iovlen = 1;
+            self.msgs.push(libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            });
+        }
+
+        loop {
+            // SAFETY: every header points into buffers owned by self
+            let n = unsafe {
+                libc::recvmmsg(
+                    sock.as_raw_fd(),
+                    self.msgs.as_mut_ptr(),
+                    self.msgs.len() as _,
+                    libc::MSG_WAITFORONE as _,
+                    ptr::null_mut(),
+                )
+            };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Returns the sender and contents of the `i`-th received datagram.
+    pub fn get(&self, i: usize) -> (Option<SocketAddr>, &[u8]) {
+        let len = self.msgs[i].msg_len as usize;
+        (to_socket_addr(&self.addrs[i]), &self.bufs[i][..len])
+    }
+}
+
+fn to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says it is a sockaddr_in
+            let addr = unsafe { &*ptr::from_ref(addr).cast::<libc::sockaddr_in>() };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says it is a sockaddr_in6
+            let addr = unsafe { &*ptr::from_ref(addr).cast::<libc::sockaddr_in6>() };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Some(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id).into())
+        }
+        _ => None,
+    }
+}
+
+/// Sends every packet to `to` with as few `sendmmsg` calls as possible.
+pub fn send_to(sock: &UdpSocket, to: SocketAddrV4, packets: &[&[u8]]) -> io::Result<()> {
+    // SAFETY: all-zero is a valid sockaddr_in
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = to.port().to_be();
+    addr.sin_addr.s_addr = u32::from(*to.ip()).to_be();
+
+    let mut iovecs: Vec<_> = packets
+        .iter()
+        .map(|i| libc::iovec {
+            iov_base: i.as_ptr().cast_mut().cast(),
+            iov_len: i.len(),
+        })
+        .collect();
+    let name = ptr::from_mut(&mut addr);
+    let mut msgs: Vec<_> = iovecs
+        .iter_mut()
+        .map(|iov| {
+            // SAFETY: all-zero is a valid msghdr
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = name.cast();
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            hdr.msg_iov = iov;
+            hdr.msg_iovlen = 1;
+            libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            }
+        })
+        .collect();
+
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let rest = &mut msgs[sent..];
+        // SAFETY: headers point to packets and addr which outlive the call
+        let n = unsafe { libc::sendmmsg(sock.as_raw_fd(), rest.as_mut_ptr(), rest.len() as _, 0) };
+        if n >= 0 {
+            sent += n as usize;
+            continue;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_and_recv_batch() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = match rx.local_addr().unwrap() {
+            SocketAddr::V4(a) => a,
+            _ => unreachable!(),
+        };
+
+        send_to(&tx, to, &[b"one", b"two", b"three"]).unwrap();
+
+        let mut batch = RecvBatch::new(8);
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let n = batch.recv(&rx).unwrap();
+            for i in 0..n {
+                let (from, data) = batch.get(i);
+                assert_eq!(from, Some(tx.local_addr().unwrap()));
+                received.push(data.to_vec());
+            }
+        }
+        assert_eq!(received, [&b"one"[..], b"two", b"three"]);
+    }
+}
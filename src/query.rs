@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use log::{debug, trace};
+use thiserror::Error;
+
+use crate::cli::QueryCli;
+use crate::config::DEFAULT_MASTER_SERVER_PORT;
+use crate::master_server::SERVER_LIST_HEADER;
+use crate::server_info::Region;
+
+/// Large enough for list responses of masters using bigger datagrams.
+const MAX_RESPONSE_SIZE: usize = 2048;
+
+/// Stop following pages if a master keeps sending data.
+const MAX_PAGES: usize = 1000;
+
+const SEED: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to resolve master address \"{0}\"")]
+    Resolve(Box<str>),
+    #[error("Invalid server list response")]
+    InvalidResponse,
+    #[error("No response from master")]
+    Timeout,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub fn run(cli: QueryCli) -> Result<(), Error> {
+    let addr = resolve(&cli.addr)?;
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    sock.connect(addr)?;
+    sock.set_read_timeout(Some(Duration::from_millis(cli.timeout)))?;
+
+    let servers = query(&sock, cli.region, &cli.filter)?;
+
+    if cli.json {
+        let list: Vec<_> = servers.iter().map(|i| i.to_string()).collect();
+        println!("{}", serde_json::Value::from(list));
+    } else {
+        for i in &servers {
+            println!("{}", i);
+        }
+    }
+    Ok(())
+}
+
+pub fn resolve(addr: &str) -> Result<SocketAddr, Error> {
+    let resolved = match addr.to_socket_addrs() {
+        Ok(iter) => iter.into_iter().find(SocketAddr::is_ipv4),
+        Err(_) => (addr, DEFAULT_MASTER_SERVER_PORT)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut iter| iter.find(SocketAddr::is_ipv4)),
+    };
+    resolved.ok_or_else(|| Error::Resolve(addr.into()))
+}
+
+/// Requests the full server list, following seed based paging.
+///
+/// Each request is answered with one or more datagrams. Reading stops at the
+/// datagram terminated by `0.0.0.0:0`, otherwise the next page is requested
+/// once the socket times out.
+fn query(sock: &UdpSocket, region: Region, filter: &str) -> Result<Vec<SocketAddrV4>, Error> {
+    let mut buf = [0; MAX_RESPONSE_SIZE];
+    let mut servers = Vec::new();
+    let mut seen = HashSet::new();
+    let mut seed = SEED;
+
+    for _ in 0..MAX_PAGES {
+        trace!("Request page with seed {}", seed);
+        sock.send(&encode_request(region, seed, filter))?;
+
+        let mut received = false;
+        let mut end = false;
+        loop {
+            let n = match sock.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if is_timeout(&e) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let (list, last) = decode_response(&buf[..n])?;
+            debug!("Received {} servers", list.len());
+            received = true;
+            end = last;
+            for addr in list {
+                if seen.insert(addr) {
+                    servers.push(addr);
+                }
+                seed = addr;
+            }
+            if end {
+                break;
+            }
+        }
+
+        if !received && servers.is_empty() {
+            return Err(Error::Timeout);
+        }
+        if !received || end {
+            break;
+        }
+    }
+
+    Ok(servers)
+}
+
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+pub fn encode_request(region: Region, seed: SocketAddrV4, filter: &str) -> Vec<u8> {
+    let mut buf = vec![b'1', region as u8];
+    buf.extend_from_slice(seed.to_string().as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(filter.as_bytes());
+    buf.push(0);
+    buf
+}
+
+/// Returns decoded addresses and `true` if the list was terminated by `0.0.0.0:0`.
+pub fn decode_response(src: &[u8]) -> Result<(Vec<SocketAddrV4>, bool), Error> {
+    let data = src
+        .strip_prefix(SERVER_LIST_HEADER)
+        .ok_or(Error::InvalidResponse)?;
+    if data.len() % 6 != 0 {
+        return Err(Error::InvalidResponse);
+    }
+
+    let mut list = Vec::with_capacity(data.len() / 6);
+    for i in data.chunks_exact(6) {
+        let ip = Ipv4Addr::new(i[0], i[1], i[2], i[3]);
+        let port = u16::from_be_bytes([i[4], i[5]]);
+        let addr = SocketAddrV4::new(ip, port);
+        if addr == SEED {
+            return Ok((list, true));
+        }
+        list.push(addr);
+    }
+    Ok((list, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::client::Packet;
+
+    #[test]
+    fn request() {
+        let seed = "1.2.3.4:27015".parse().unwrap();
+        let buf = encode_request(Region::Europe, seed, "\\gamedir\\cstrike");
+        assert_eq!(buf, b"1\x031.2.3.4:27015\0\\gamedir\\cstrike\0");
+        match Packet::decode(&buf) {
+            Ok(Packet::QueryServers(Region::Europe, Some(seed), filter)) => {
+                assert_eq!(seed.to_string(), "1.2.3.4:27015");
+                assert_eq!(&*filter, b"\\gamedir\\cstrike");
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+    }
+
+    #[test]
+    fn response() {
+        let a = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 27015);
+        let b = SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 27016);
+
+        let mut buf = SERVER_LIST_HEADER.to_vec();
+        buf.extend_from_slice(&[1, 2, 3, 4, 0x69, 0x87, 5, 6, 7, 8, 0x69, 0x88]);
+        assert_eq!(decode_response(&buf).unwrap(), (vec![a, b], false));
+
+        buf.extend_from_slice(&[0; 6]);
+        assert_eq!(decode_response(&buf).unwrap(), (vec![a, b], true));
+
+        buf.push(0);
+        assert!(matches!(decode_response(&buf), Err(Error::InvalidResponse)));
+        assert!(matches!(
+            decode_response(b"\xff\xff\xff\xffs\n"),
+            Err(Error::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn stop_at_end() {
+        let master = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.connect(master.local_addr().unwrap()).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0; MAX_RESPONSE_SIZE];
+            let (_, from) = master.recv_from(&mut buf).unwrap();
+            let mut list = SERVER_LIST_HEADER.to_vec();
+            list.extend_from_slice(&[1, 2, 3, 4, 0x69, 0x87]);
+            master.send_to(&list, from).unwrap();
+            list.extend_from_slice(&[0; 6]);
+            master.send_to(&list, from).unwrap();
+        });
+
+        let start = Instant::now();
+        let servers = query(&sock, Region::RestOfTheWorld, "").unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(servers, ["1.2.3.4:27015".parse::<SocketAddrV4>().unwrap()]);
+        handle.join().unwrap();
+    }
+}
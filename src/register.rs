@@ -0,0 +1,94 @@
+use std::io;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info};
+use thiserror::Error;
+
+use crate::cli::RegisterCli;
+use crate::master_server::CHALLENGE_RESPONSE_HEADER;
+use crate::query::{self, resolve};
+use crate::server_info::ServerInfo;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Query(#[from] query::Error),
+    #[error("Invalid challenge response")]
+    InvalidResponse,
+    #[error("No challenge response from master")]
+    Timeout,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub fn run(cli: RegisterCli) -> Result<(), Error> {
+    let addr = resolve(&cli.addr)?;
+    let sock = UdpSocket::bind(cli.bind)?;
+    sock.connect(addr)?;
+    sock.set_read_timeout(Some(Duration::from_millis(cli.timeout)))?;
+    info!("Local address: {}", sock.local_addr()?);
+
+    if cli.remove {
+        sock.send(b"b\n")?;
+        info!("{}: Sent remove", addr);
+        return Ok(());
+    }
+
+    loop {
+        heartbeat(&sock, &cli.info)?;
+        info!("{}: Sent heartbeat", addr);
+        match cli.interval {
+            Some(secs) => thread::sleep(Duration::from_secs(secs)),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Runs `q` -> challenge -> `0\n` handshake.
+pub fn heartbeat<T: AsRef<str>>(sock: &UdpSocket, info: &ServerInfo<T>) -> Result<(), Error> {
+    sock.send(b"q")?;
+
+    let mut buf = [0; 64];
+    let n = match sock.recv(&mut buf) {
+        Ok(n) => n,
+        Err(e) if query::is_timeout(&e) => return Err(Error::Timeout),
+        Err(e) => return Err(e.into()),
+    };
+    let challenge = decode_challenge(&buf[..n])?;
+    debug!("Received challenge {}", challenge);
+
+    let mut buf = b"0\n".to_vec();
+    info.encode(Some(challenge), &mut buf);
+    sock.send(&buf)?;
+    Ok(())
+}
+
+fn decode_challenge(src: &[u8]) -> Result<u32, Error> {
+    match src.strip_prefix(CHALLENGE_RESPONSE_HEADER) {
+        Some([b0, b1, b2, b3, ..]) => Ok(u32::from_le_bytes([*b0, *b1, *b2, *b3])),
+        _ => Err(Error::InvalidResponse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge() {
+        assert_eq!(
+            decode_challenge(b"\xff\xff\xff\xffs\n\x01\x02\x03\x04").unwrap(),
+            0x04030201
+        );
+        assert_eq!(
+            decode_challenge(b"\xff\xff\xff\xffs\n\x01\x02\x03\x04\x05\x06\x07\x08").unwrap(),
+            0x04030201
+        );
+        assert!(matches!(
+            decode_challenge(b"\xff\xff\xff\xffs\n\x01\x02\x03"),
+            Err(Error::InvalidResponse)
+        ));
+    }
+}
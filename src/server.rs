@@ -1,23 +1,59 @@
 use crate::filter::FilterFlags;
-use crate::server_info::{Region, ServerInfo};
+use crate::intern::{Atom, Interner};
+use crate::server_info::{Os, Region, ServerInfo, ServerType};
+
+/// Protocol family a server registered with.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Family {
+    /// Valve GoldSrc/Source and Xash3D FWGS.
+    #[default]
+    Valve,
+    /// Quake III Arena and DarkPlaces derived games.
+    Dpmaster,
+}
 
 #[derive(Clone, Debug)]
 pub struct Server {
-    pub version: Box<str>,
-    pub gamedir: Box<str>,
-    pub map: Box<str>,
+    pub version: Atom,
+    pub gamedir: Atom,
+    pub map: Atom,
+    pub product: Atom,
     pub flags: FilterFlags,
+    pub server_type: ServerType,
+    pub os: Os,
     pub region: Region,
+    pub players: u8,
+    pub max: u8,
+    pub protocol: u8,
+    pub family: Family,
 }
 
 impl Server {
-    pub fn new(info: &ServerInfo<&str>) -> Self {
+    pub fn new(info: &ServerInfo<&str>, strings: &mut Interner) -> Self {
         Self {
-            version: info.version.to_string().into_boxed_str(),
-            gamedir: info.gamedir.to_string().into_boxed_str(),
-            map: info.map.to_string().into_boxed_str(),
+            version: strings.intern(info.version),
+            gamedir: strings.intern(info.gamedir),
+            map: strings.intern(info.map),
+            product: strings.intern(info.product),
             flags: FilterFlags::from(info),
+            server_type: info.server_type,
+            os: info.os,
             region: info.region,
+            players: info.players,
+            max: info.max,
+            protocol: info.protocol,
+            family: Family::Valve,
         }
     }
+
+    /// Returns `true` if the server announced `\lan\1`.
+    pub fn is_lan(&self) -> bool {
+        self.flags.contains(FilterFlags::LAN)
+    }
+
+    /// Returns `true` if the server announced `\nat\1` and needs clients
+    /// announced to punch a hole.
+    pub fn is_nat(&self) -> bool {
+        self.flags.contains(FilterFlags::NAT)
+    }
 }
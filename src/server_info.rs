@@ -1,4 +1,5 @@
 use std::fmt;
+use std::str::FromStr;
 
 use bitflags::bitflags;
 use log::{debug, log_enabled, Level};
@@ -25,6 +26,7 @@ pub enum Os {
     Unknown,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for Os {
     fn default() -> Os {
         Os::Unknown
@@ -44,6 +46,18 @@ impl ParseValue<'_> for Os {
     }
 }
 
+impl Os {
+    /// Heartbeat value of the `os` key.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Os::Linux => "l",
+            Os::Windows => "w",
+            Os::Mac => "m",
+            Os::Unknown => "u",
+        }
+    }
+}
+
 impl fmt::Display for Os {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let s = match self {
@@ -65,6 +79,7 @@ pub enum ServerType {
     Unknown,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for ServerType {
     fn default() -> Self {
         Self::Unknown
@@ -84,6 +99,18 @@ impl ParseValue<'_> for ServerType {
     }
 }
 
+impl ServerType {
+    /// Heartbeat value of the `type` key.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Dedicated => "d",
+            Self::Local => "l",
+            Self::Proxy => "p",
+            Self::Unknown => "u",
+        }
+    }
+}
+
 impl fmt::Display for ServerType {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use ServerType as E;
@@ -99,7 +126,7 @@ impl fmt::Display for ServerType {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum Region {
     USEastCoast = 0x00,
@@ -113,6 +140,7 @@ pub enum Region {
     RestOfTheWorld = 0xff,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for Region {
     fn default() -> Self {
         Self::RestOfTheWorld
@@ -138,6 +166,18 @@ impl TryFrom<u8> for Region {
     }
 }
 
+impl FromStr for Region {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s
+            .parse::<u8>()
+            .or_else(|_| s.parse::<i8>().map(|i| i as u8))
+            .map_err(|_| ParserError::InvalidInteger)?;
+        Self::try_from(value).map_err(|_| Error::InvalidRegion)
+    }
+}
+
 impl ParseValue<'_> for Region {
     type Err = Error;
 
@@ -154,6 +194,8 @@ bitflags! {
         const PASSWORD  = 1 << 1;
         const SECURE    = 1 << 2;
         const LAN       = 1 << 3;
+        /// Server is behind NAT (Xash3D FWGS)
+        const NAT       = 1 << 4;
     }
 }
 
@@ -187,6 +229,37 @@ where
     }
 }
 
+impl<T: AsRef<str>> ServerInfo<T> {
+    /// Encodes heartbeat key-values terminated by a newline, the inverse of `from_bytes`.
+    pub fn encode(&self, challenge: Option<u32>, out: &mut Vec<u8>) {
+        fn put(out: &mut Vec<u8>, key: &str, value: impl fmt::Display) {
+            out.extend_from_slice(format!("\\{}\\{}", key, value).as_bytes());
+        }
+
+        put(out, "protocol", self.protocol);
+        if let Some(challenge) = challenge {
+            put(out, "challenge", challenge);
+        }
+        put(out, "players", self.players);
+        put(out, "max", self.max);
+        put(out, "bots", self.flags.contains(ServerFlags::BOTS) as u8);
+        put(out, "gamedir", self.gamedir.as_ref());
+        put(out, "map", self.map.as_ref());
+        put(out, "type", self.server_type.code());
+        put(out, "password", self.flags.contains(ServerFlags::PASSWORD) as u8);
+        put(out, "os", self.os.code());
+        put(out, "secure", self.flags.contains(ServerFlags::SECURE) as u8);
+        put(out, "lan", self.flags.contains(ServerFlags::LAN) as u8);
+        put(out, "version", self.version.as_ref());
+        put(out, "region", self.region as u8);
+        put(out, "product", self.product.as_ref());
+        if self.flags.contains(ServerFlags::NAT) {
+            put(out, "nat", 1);
+        }
+        out.push(b'\n');
+    }
+}
+
 impl<'a, T> ParseValue<'a> for (Option<u32>, ServerInfo<T>)
 where
     T: 'a + Default + ParseValue<'a, Err = ParserError>,
@@ -220,6 +293,7 @@ where
                 b"password" => info.flags.set(ServerFlags::PASSWORD, p.parse()?),
                 b"secure" => info.flags.set(ServerFlags::SECURE, p.parse()?),
                 b"lan" => info.flags.set(ServerFlags::LAN, p.parse()?),
+                b"nat" => info.flags.set(ServerFlags::NAT, p.parse()?),
                 _ => {
                     // skip unknown fields
                     let value = p.parse_bytes()?;
@@ -277,6 +351,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn region_from_str() {
+        assert_eq!("3".parse(), Ok(Region::Europe));
+        assert_eq!("255".parse(), Ok(Region::RestOfTheWorld));
+        assert_eq!("-1".parse(), Ok(Region::RestOfTheWorld));
+        assert_eq!("8".parse::<Region>(), Err(Error::InvalidRegion));
+        assert_eq!(
+            "eu".parse::<Region>(),
+            Err(Error::Parser(ParserError::InvalidInteger))
+        );
+    }
+
     #[test]
     fn parse_server_info() {
         let buf = b"\
@@ -293,6 +379,7 @@ mod tests {
             \\os\\l\
             \\secure\\1\
             \\lan\\1\
+            \\nat\\1\
             \\version\\1.1.2.5\
             \\region\\-1\
             \\product\\cstrike\
@@ -320,4 +407,33 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn encode_server_info() {
+        let info = ServerInfo::<&str> {
+            protocol: 48,
+            players: 3,
+            max: 16,
+            gamedir: "valve",
+            map: "crossfire",
+            server_type: ServerType::Proxy,
+            os: Os::Windows,
+            version: "1.1.2.7",
+            region: Region::Europe,
+            product: "valve",
+            flags: ServerFlags::SECURE | ServerFlags::BOTS,
+        };
+
+        let mut buf = Vec::new();
+        info.encode(Some(42), &mut buf);
+        assert_eq!(
+            &buf[..],
+            &b"\
+                \\protocol\\48\\challenge\\42\\players\\3\\max\\16\\bots\\1\
+                \\gamedir\\valve\\map\\crossfire\\type\\p\\password\\0\\os\\w\
+                \\secure\\1\\lan\\0\\version\\1.1.2.7\\region\\3\\product\\valve\n\
+            "[..]
+        );
+        assert_eq!(ServerInfo::from_bytes(&buf), Ok((Some(42), info, &b""[..])));
+    }
 }
@@ -0,0 +1,503 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::mem::{self, size_of};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::Deref;
+
+use crate::filter::Filter;
+use crate::intern::{Atom, Interner};
+use crate::server::{Family, Server};
+use crate::server_info::Region;
+
+/// Number of bits in `FilterFlags`.
+const FLAG_BITS: usize = u16::BITS as usize;
+
+/// HashMap entry to keep tracking creation time.
+pub struct Entry<T> {
+    pub time: u32,
+    pub value: T,
+}
+
+impl<T> Entry<T> {
+    pub fn new(time: u32, value: T) -> Self {
+        Self { time, value }
+    }
+
+    pub fn is_valid(&self, now: u32, duration: u32) -> bool {
+        now.saturating_sub(self.time) < duration
+    }
+}
+
+impl Entry<Server> {
+    /// `RestOfTheWorld` matches servers of every region.
+    pub fn matches(&self, addr: SocketAddrV4, region: Region, filter: &Filter) -> bool {
+        self.family == Family::Valve
+            && (region == Region::RestOfTheWorld || self.region == region)
+            && filter.matches(addr, self)
+    }
+}
+
+impl<T> Deref for Entry<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+/// Keys in insertion order for time-based expiry.
+///
+/// Every entry of a table has the same timeout, so the front of the queue always
+/// expires first. Refreshed or removed entries leave stale items behind which are
+/// skipped by comparing the entry time.
+pub struct ExpiryQueue<K> {
+    queue: VecDeque<(u32, K)>,
+}
+
+impl<K> Default for ExpiryQueue<K> {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: Copy> ExpiryQueue<K> {
+    pub fn push(&mut self, time: u32, key: K) {
+        self.queue.push_back((time, key));
+    }
+
+    /// Pops the next key with insertion time older than `timeout` seconds.
+    pub fn pop_expired(&mut self, now: u32, timeout: u32) -> Option<(u32, K)> {
+        match self.queue.front() {
+            Some(&(time, _)) if now.saturating_sub(time) >= timeout => self.queue.pop_front(),
+            _ => None,
+        }
+    }
+
+    /// Returns the oldest item for which `is_live` holds, stale items in front
+    /// of it are dropped.
+    pub fn oldest(&mut self, mut is_live: impl FnMut(u32, &K) -> bool) -> Option<(u32, K)> {
+        while let Some(&(time, key)) = self.queue.front() {
+            if is_live(time, &key) {
+                return Some((time, key));
+            }
+            self.queue.pop_front();
+        }
+        None
+    }
+}
+
+type AddrSet = HashSet<SocketAddrV4>;
+
+/// Server storage with secondary indexes by region, gamedir and flags.
+///
+/// Queries start from the smallest index matching the filter instead of
+/// scanning every server.
+#[derive(Default)]
+pub struct ServerTable {
+    servers: HashMap<SocketAddrV4, Entry<Server>>,
+    by_region: HashMap<Region, AddrSet>,
+    by_gamedir: HashMap<Atom, AddrSet>,
+    by_flag: [AddrSet; FLAG_BITS],
+    by_ip: HashMap<Ipv4Addr, AddrSet>,
+    expiry: ExpiryQueue<SocketAddrV4>,
+    /// Servers which never expire and are never evicted.
+    pinned: AddrSet,
+    strings: Interner,
+    /// Whether servers were removed since strings were last collected.
+    released: bool,
+}
+
+impl ServerTable {
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn contains(&self, addr: &SocketAddrV4) -> bool {
+        self.servers.contains_key(addr)
+    }
+
+    pub fn get(&self, addr: &SocketAddrV4) -> Option<&Entry<Server>> {
+        self.servers.get(addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddrV4, &Entry<Server>)> {
+        self.servers.iter()
+    }
+
+    /// Returns the number of servers registered from `ip`.
+    pub fn count_ip(&self, ip: &Ipv4Addr) -> usize {
+        self.by_ip.get(ip).map_or(0, |i| i.len())
+    }
+
+    pub fn pin(&mut self, addr: SocketAddrV4) {
+        self.pinned.insert(addr);
+    }
+
+    pub fn is_pinned(&self, addr: &SocketAddrV4) -> bool {
+        self.pinned.contains(addr)
+    }
+
+    /// Returns the least recently updated server which is not pinned.
+    pub fn oldest(&mut self) -> Option<SocketAddrV4> {
+        let servers = &self.servers;
+        let pinned = &self.pinned;
+        self.expiry
+            .oldest(|time, addr| {
+                servers.get(addr).is_some_and(|i| i.time == time) && !pinned.contains(addr)
+            })
+            .map(|i| i.1)
+    }
+
+    pub fn strings(&self) -> &Interner {
+        &self.strings
+    }
+
+    pub fn strings_mut(&mut self) -> &mut Interner {
+        &mut self.strings
+    }
+
+    /// Approximate memory used by servers, indexes and interned strings.
+    pub fn memory(&self) -> usize {
+        let addr = size_of::<SocketAddrV4>() + 1;
+        let sets = self
+            .by_region
+            .values()
+            .chain(self.by_gamedir.values())
+            .chain(self.by_ip.values())
+            .chain(&self.by_flag)
+            .map(|i| i.capacity() * addr)
+            .sum::<usize>();
+        let indexes =
+            (self.by_region.capacity() + self.by_gamedir.capacity() + self.by_ip.capacity())
+                * (size_of::<Atom>() + size_of::<AddrSet>() + 1);
+        let servers = self.servers.capacity() * (size_of::<(SocketAddrV4, Entry<Server>)>() + 1);
+        let expiry = self.expiry.queue.capacity() * size_of::<(u32, SocketAddrV4)>();
+        servers + sets + indexes + expiry + self.strings.memory()
+    }
+
+    /// Inserts or replaces the server, returning the previous entry.
+    pub fn insert(&mut self, addr: SocketAddrV4, entry: Entry<Server>) -> Option<Entry<Server>> {
+        let old = self.remove(&addr);
+        self.index(addr, &entry);
+        self.expiry.push(entry.time, addr);
+        self.servers.insert(addr, entry);
+        old
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddrV4) -> Option<Entry<Server>> {
+        let old = self.servers.remove(addr)?;
+        self.unindex(*addr, &old);
+        self.released = true;
+        Some(old)
+    }
+
+    /// Removes and returns servers not updated for `timeout` seconds, pinned
+    /// servers are kept.
+    ///
+    /// Also drops interned strings released since the previous call, strings of
+    /// the returned servers are collected on the next call. The interner is only
+    /// scanned if servers were removed or replaced.
+    pub fn expire(&mut self, now: u32, timeout: u32) -> Vec<(SocketAddrV4, Entry<Server>)> {
+        if mem::take(&mut self.released) {
+            self.strings.collect();
+        }
+        let mut removed = Vec::new();
+        while let Some((time, addr)) = self.expiry.pop_expired(now, timeout) {
+            if self.servers.get(&addr).is_some_and(|i| i.time == time)
+                && !self.pinned.contains(&addr)
+            {
+                removed.extend(self.remove(&addr).map(|i| (addr, i)));
+            }
+        }
+        removed
+    }
+
+    /// Returns servers in `region` matching `filter`.
+    pub fn query<'a>(
+        &'a self,
+        region: Region,
+        filter: &'a Filter,
+    ) -> impl Iterator<Item = (&'a SocketAddrV4, &'a Entry<Server>)> + 'a {
+        self.candidates(region, filter)
+            .filter(move |(addr, entry)| entry.matches(**addr, region, filter))
+    }
+
+    /// Returns servers running `gamedir`.
+    pub fn by_gamedir<'a>(
+        &'a self,
+        gamedir: &str,
+    ) -> impl Iterator<Item = (&'a SocketAddrV4, &'a Entry<Server>)> + 'a {
+        self.by_gamedir
+            .get(gamedir)
+            .into_iter()
+            .flatten()
+            .filter_map(|addr| self.servers.get_key_value(addr))
+    }
+
+    /// Picks the smallest index which contains every possible match.
+    fn candidates<'a>(
+        &'a self,
+        region: Region,
+        filter: &Filter,
+    ) -> Box<dyn Iterator<Item = (&'a SocketAddrV4, &'a Entry<Server>)> + 'a> {
+        if let Some(addr) = filter.gameaddr.filter(|i| i.port() != 0) {
+            return Box::new(self.servers.get_key_value(&addr).into_iter());
+        }
+
+        // no index means every server is a candidate
+        let mut best = None;
+        let mut empty = false;
+        let mut consider = |set: Option<&'a AddrSet>| match set {
+            None => empty = true,
+            Some(set) if best.is_none_or(|cur: &AddrSet| set.len() < cur.len()) => best = Some(set),
+            _ => {}
+        };
+        if region != Region::RestOfTheWorld {
+            consider(self.by_region.get(&region));
+        }
+        if let Some(gamedir) = filter.gamedir {
+            consider(self.by_gamedir.get(gamedir));
+        }
+        let required = filter.flags & filter.flags_mask;
+        for (bit, set) in self.by_flag.iter().enumerate() {
+            if required.bits() & (1 << bit) != 0 {
+                consider(Some(set));
+            }
+        }
+
+        match best {
+            _ if empty => Box::new(std::iter::empty()),
+            Some(set) => Box::new(
+                set.iter()
+                    .filter_map(|addr| self.servers.get_key_value(addr)),
+            ),
+            None => Box::new(self.servers.iter()),
+        }
+    }
+
+    fn index(&mut self, addr: SocketAddrV4, entry: &Entry<Server>) {
+        add_index(&mut self.by_region, &entry.region, addr);
+        add_index(&mut self.by_gamedir, &entry.gamedir, addr);
+        add_index(&mut self.by_ip, addr.ip(), addr);
+        for (bit, set) in self.by_flag.iter_mut().enumerate() {
+            if entry.flags.bits() & (1 << bit) != 0 {
+                set.insert(addr);
+            }
+        }
+    }
+
+    fn unindex(&mut self, addr: SocketAddrV4, entry: &Entry<Server>) {
+        remove_index(&mut self.by_region, &entry.region, addr);
+        remove_index(&mut self.by_gamedir, &entry.gamedir, addr);
+        remove_index(&mut self.by_ip, addr.ip(), addr);
+        for (bit, set) in self.by_flag.iter_mut().enumerate() {
+            if entry.flags.bits() & (1 << bit) != 0 {
+                set.remove(&addr);
+            }
+        }
+    }
+}
+
+fn add_index<K: Eq + Hash + Clone>(index: &mut HashMap<K, AddrSet>, key: &K, addr: SocketAddrV4) {
+    match index.get_mut(key) {
+        Some(set) => {
+            set.insert(addr);
+        }
+        None => {
+            index.insert(key.clone(), AddrSet::from([addr]));
+        }
+    }
+}
+
+fn remove_index<K: Eq + Hash>(index: &mut HashMap<K, AddrSet>, key: &K, addr: SocketAddrV4) {
+    if let Some(set) = index.get_mut(key) {
+        set.remove(&addr);
+        if set.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server_info::ServerInfo;
+
+    fn insert(table: &mut ServerTable, port: u16, time: u32, info: &[u8]) {
+        let (_, info, _) = ServerInfo::<&str>::from_bytes(info).unwrap();
+        let server = Server::new(&info, table.strings_mut());
+        table.insert(addr(port), Entry::new(time, server));
+    }
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([1, 2, 3, 4].into(), port)
+    }
+
+    fn query(table: &ServerTable, region: Region, filter: &[u8]) -> Vec<u16> {
+        let filter = Filter::from_bytes(filter).unwrap();
+        let mut ports: Vec<_> = table.query(region, &filter).map(|i| i.0.port()).collect();
+        ports.sort();
+        ports
+    }
+
+    #[test]
+    fn indexed_query() {
+        let mut table = ServerTable::default();
+        insert(&mut table, 1, 0, b"\\gamedir\\valve\\region\\3");
+        insert(
+            &mut table,
+            2,
+            0,
+            b"\\gamedir\\cstrike\\region\\3\\secure\\1",
+        );
+        insert(&mut table, 3, 0, b"\\gamedir\\cstrike\\region\\3");
+        insert(
+            &mut table,
+            4,
+            0,
+            b"\\gamedir\\cstrike\\region\\4\\secure\\1",
+        );
+
+        let eu = Region::Europe;
+        assert_eq!(query(&table, eu, b""), [1, 2, 3]);
+        assert_eq!(query(&table, eu, b"\\gamedir\\cstrike"), [2, 3]);
+        assert_eq!(query(&table, eu, b"\\gamedir\\cstrike\\secure\\1"), [2]);
+        assert_eq!(query(&table, eu, b"\\gamedir\\cstrike\\secure\\0"), [3]);
+        assert_eq!(query(&table, eu, b"\\gamedir\\dod"), [] as [u16; 0]);
+        assert_eq!(query(&table, Region::Asia, b"\\secure\\1"), [4]);
+        assert_eq!(query(&table, Region::Africa, b""), [] as [u16; 0]);
+        assert_eq!(query(&table, eu, b"\\gameaddr\\1.2.3.4:2"), [2]);
+        assert_eq!(query(&table, eu, b"\\gameaddr\\1.2.3.4:4"), [] as [u16; 0]);
+        assert_eq!(query(&table, eu, b"\\gameaddr\\1.2.3.4"), [1, 2, 3]);
+
+        let all = Region::RestOfTheWorld;
+        assert_eq!(query(&table, all, b""), [1, 2, 3, 4]);
+        assert_eq!(query(&table, all, b"\\secure\\1"), [2, 4]);
+        assert_eq!(query(&table, all, b"\\gamedir\\dod"), [] as [u16; 0]);
+        assert_eq!(query(&table, all, b"\\gameaddr\\1.2.3.4:4"), [4]);
+    }
+
+    #[test]
+    fn update_indexes() {
+        let mut table = ServerTable::default();
+        insert(&mut table, 1, 0, b"\\gamedir\\valve\\region\\3\\secure\\1");
+        insert(&mut table, 1, 0, b"\\gamedir\\cstrike\\region\\3");
+
+        let eu = Region::Europe;
+        assert_eq!(query(&table, eu, b"\\gamedir\\valve"), [] as [u16; 0]);
+        assert_eq!(query(&table, eu, b"\\gamedir\\cstrike"), [1]);
+        assert_eq!(query(&table, eu, b"\\secure\\1"), [] as [u16; 0]);
+
+        insert(&mut table, 2, 0, b"\\gamedir\\cstrike\\region\\3");
+        table.remove(&addr(1));
+        assert_eq!(query(&table, eu, b"\\gamedir\\cstrike"), [2]);
+
+        let removed = table.expire(10, 10);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(table.len(), 0);
+        assert!(table.by_region.is_empty());
+        assert!(table.by_gamedir.is_empty());
+        assert!(table.by_flag.iter().all(|i| i.is_empty()));
+
+        // strings of removed servers are released by the next collection
+        drop(removed);
+        table.expire(10, 10);
+        assert_eq!(table.strings().len(), 0);
+        assert!(!table.released);
+    }
+
+    #[test]
+    fn oldest() {
+        let mut table = ServerTable::default();
+        let other = SocketAddrV4::new([5, 6, 7, 8].into(), 1);
+        let (_, info, _) = ServerInfo::<&str>::from_bytes(b"").unwrap();
+        let server = Server::new(&info, table.strings_mut());
+        table.insert(other, Entry::new(0, server));
+        insert(&mut table, 1, 1, b"");
+        insert(&mut table, 2, 2, b"");
+        insert(&mut table, 3, 3, b"");
+        insert(&mut table, 1, 4, b"");
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert_eq!(table.count_ip(&ip), 3);
+        assert_eq!(table.count_ip(other.ip()), 1);
+        assert_eq!(table.oldest(), Some(other));
+
+        table.remove(&other);
+        assert_eq!(table.oldest(), Some(addr(2)));
+        table.remove(&addr(2));
+        assert_eq!(table.oldest(), Some(addr(3)));
+    }
+
+    #[test]
+    fn pinned() {
+        let mut table = ServerTable::default();
+        table.pin(addr(1));
+        insert(&mut table, 1, 0, b"\\gamedir\\valve");
+        insert(&mut table, 2, 1, b"\\gamedir\\valve");
+
+        assert_eq!(table.oldest(), Some(addr(2)));
+
+        let removed = table.expire(20, 10);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, addr(2));
+        assert!(table.contains(&addr(1)));
+        assert_eq!(table.oldest(), None);
+
+        // refreshed by a heartbeat and still kept after it stops
+        insert(&mut table, 1, 30, b"\\gamedir\\cstrike");
+        assert!(table.expire(50, 10).is_empty());
+        assert_eq!(&*table.servers[&addr(1)].gamedir, "cstrike");
+    }
+
+    #[test]
+    fn interned_strings() {
+        let mut table = ServerTable::default();
+        insert(&mut table, 1, 0, b"\\gamedir\\cstrike\\map\\de_dust2");
+        insert(&mut table, 2, 0, b"\\gamedir\\cstrike\\map\\de_dust2");
+        insert(&mut table, 3, 0, b"\\gamedir\\cstrike\\map\\cs_office");
+        // version, gamedir and two maps
+        assert_eq!(table.strings().len(), 4);
+
+        table.remove(&addr(3));
+        table.expire(0, 10);
+        assert_eq!(table.strings().len(), 3);
+        // product is kept for audit records of expired and evicted servers
+        assert!(size_of::<Entry<Server>>() <= 56);
+    }
+
+    #[test]
+    fn expire() {
+        let mut table = ServerTable::default();
+        insert(&mut table, 1, 0, b"");
+        insert(&mut table, 2, 1, b"");
+        insert(&mut table, 3, 2, b"");
+        // refresh and remove leave stale queue items
+        insert(&mut table, 1, 3, b"");
+        table.remove(&addr(2));
+
+        assert!(table.expire(9, 10).is_empty());
+        assert!(table.expire(11, 10).is_empty());
+        let removed: Vec<_> = table.expire(12, 10).iter().map(|i| i.0.port()).collect();
+        assert_eq!(removed, [3]);
+        let removed: Vec<_> = table.expire(13, 10).iter().map(|i| i.0.port()).collect();
+        assert_eq!(removed, [1]);
+        assert_eq!(table.len(), 0);
+        assert!(table.expiry.queue.is_empty());
+    }
+
+    #[test]
+    fn time_ahead() {
+        // another thread may insert after the caller read `now`
+        let entry = Entry::new(11, ());
+        assert!(entry.is_valid(10, 5));
+
+        let mut table = ServerTable::default();
+        insert(&mut table, 1, 11, b"");
+        assert!(table.expire(10, 5).is_empty());
+        assert!(table.contains(&addr(1)));
+    }
+}
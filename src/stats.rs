@@ -0,0 +1,140 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Monotonic counters, reported as deltas since the previous flush.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Counter {
+    PacketChallenge,
+    PacketServerAdd,
+    PacketServerRemove,
+    PacketQueryServers,
+    PacketServerInfo,
+    PacketInvalid,
+    PacketDpHeartbeat,
+    PacketDpInfoResponse,
+    PacketDpGetServers,
+    ServerAdded,
+    ServerUpdated,
+    QueryResults,
+    NatAnnounce,
+    ChallengeEvicted,
+    ChallengeRejected,
+    ChallengeInvalid,
+    ServerEvicted,
+    ServerRejected,
+    ServerRejectedPerIp,
+    ServerRejectedPrivate,
+    ServerRejectedGame,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 21] = [
+        Counter::PacketChallenge,
+        Counter::PacketServerAdd,
+        Counter::PacketServerRemove,
+        Counter::PacketQueryServers,
+        Counter::PacketServerInfo,
+        Counter::PacketInvalid,
+        Counter::PacketDpHeartbeat,
+        Counter::PacketDpInfoResponse,
+        Counter::PacketDpGetServers,
+        Counter::ServerAdded,
+        Counter::ServerUpdated,
+        Counter::QueryResults,
+        Counter::NatAnnounce,
+        Counter::ChallengeEvicted,
+        Counter::ChallengeRejected,
+        Counter::ChallengeInvalid,
+        Counter::ServerEvicted,
+        Counter::ServerRejected,
+        Counter::ServerRejectedPerIp,
+        Counter::ServerRejectedPrivate,
+        Counter::ServerRejectedGame,
+    ];
+
+    /// Returns metric name and an optional `type` tag.
+    pub fn metric(self) -> (&'static str, Option<&'static str>) {
+        use Counter as E;
+
+        match self {
+            E::PacketChallenge => ("packets", Some("challenge")),
+            E::PacketServerAdd => ("packets", Some("server_add")),
+            E::PacketServerRemove => ("packets", Some("server_remove")),
+            E::PacketQueryServers => ("packets", Some("query_servers")),
+            E::PacketServerInfo => ("packets", Some("server_info")),
+            E::PacketInvalid => ("packets", Some("invalid")),
+            E::PacketDpHeartbeat => ("packets", Some("dp_heartbeat")),
+            E::PacketDpInfoResponse => ("packets", Some("dp_info_response")),
+            E::PacketDpGetServers => ("packets", Some("dp_getservers")),
+            E::ServerAdded => ("servers.added", None),
+            E::ServerUpdated => ("servers.updated", None),
+            E::QueryResults => ("query.results", None),
+            E::NatAnnounce => ("nat.announces", None),
+            E::ChallengeEvicted => ("challenges.evicted", None),
+            E::ChallengeRejected => ("challenges.rejected", None),
+            E::ChallengeInvalid => ("challenges.invalid", None),
+            E::ServerEvicted => ("servers.evicted", Some("max_servers")),
+            E::ServerRejected => ("servers.rejected", Some("max_servers")),
+            E::ServerRejectedPerIp => ("servers.rejected", Some("max_servers_per_ip")),
+            E::ServerRejectedPrivate => ("servers.rejected", Some("private")),
+            E::ServerRejectedGame => ("servers.rejected", Some("game")),
+        }
+    }
+}
+
+/// Point-in-time values.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Gauge {
+    Servers,
+    Challenges,
+    /// Number of interned strings.
+    Strings,
+    /// Approximate memory used by the server table divided by server count.
+    ServerMemory,
+}
+
+impl Gauge {
+    pub const ALL: [Gauge; 4] = [
+        Gauge::Servers,
+        Gauge::Challenges,
+        Gauge::Strings,
+        Gauge::ServerMemory,
+    ];
+
+    pub fn metric(self) -> &'static str {
+        match self {
+            Gauge::Servers => "servers",
+            Gauge::Challenges => "challenges",
+            Gauge::Strings => "strings",
+            Gauge::ServerMemory => "servers.memory",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    counters: [AtomicU64; Counter::ALL.len()],
+    gauges: [AtomicU64; Gauge::ALL.len()],
+}
+
+impl Stats {
+    pub fn incr(&self, counter: Counter) {
+        self.add(counter, 1);
+    }
+
+    pub fn add(&self, counter: Counter, n: u64) {
+        self.counters[counter as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the counter value and resets it to zero.
+    pub fn take(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize].swap(0, Ordering::Relaxed)
+    }
+
+    pub fn set(&self, gauge: Gauge, value: usize) {
+        self.gauges[gauge as usize].store(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, gauge: Gauge) -> u64 {
+        self.gauges[gauge as usize].load(Ordering::Relaxed)
+    }
+}
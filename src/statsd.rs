@@ -0,0 +1,146 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, trace, warn};
+
+use crate::config::StatsdConfig;
+use crate::stats::{Counter, Gauge, Stats};
+
+/// Keep datagrams below a common path MTU.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+struct Emitter {
+    sock: UdpSocket,
+    cfg: StatsdConfig,
+    stats: Arc<Stats>,
+}
+
+impl Emitter {
+    fn run(&self) {
+        let interval = Duration::from_secs(self.cfg.interval.max(1).into());
+        loop {
+            thread::sleep(interval);
+            if let Err(e) = self.flush() {
+                warn!("StatsD: failed to send metrics: {}", e);
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut buf = String::with_capacity(MAX_DATAGRAM_SIZE);
+        let mut line = String::new();
+
+        let counters = Counter::ALL
+            .iter()
+            .map(|&c| (c.metric(), self.stats.take(c), "c"));
+        let gauges = Gauge::ALL
+            .iter()
+            .map(|&g| ((g.metric(), None), self.stats.get(g), "g"));
+
+        for ((name, tag), value, kind) in counters.chain(gauges) {
+            if kind == "c" && value == 0 {
+                continue;
+            }
+            line.clear();
+            format_metric(&mut line, &self.cfg, name, tag, value, kind);
+            if !buf.is_empty() && buf.len() + line.len() + 1 > MAX_DATAGRAM_SIZE {
+                self.send(&buf)?;
+                buf.clear();
+            }
+            if !buf.is_empty() {
+                buf.push('\n');
+            }
+            buf.push_str(&line);
+        }
+
+        if !buf.is_empty() {
+            self.send(&buf)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, buf: &str) -> io::Result<()> {
+        trace!("StatsD: send {:?}", buf);
+        self.sock.send(buf.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn format_metric(
+    out: &mut String,
+    cfg: &StatsdConfig,
+    name: &str,
+    tag: Option<&str>,
+    value: u64,
+    kind: &str,
+) {
+    if !cfg.prefix.is_empty() {
+        out.push_str(&cfg.prefix);
+        out.push('.');
+    }
+    out.push_str(name);
+    match tag {
+        Some(tag) if cfg.dogstatsd => {
+            let _ = write!(out, ":{}|{}|#type:{}", value, kind, tag);
+        }
+        Some(tag) => {
+            let _ = write!(out, ".{}:{}|{}", tag, value, kind);
+        }
+        None => {
+            let _ = write!(out, ":{}|{}", value, kind);
+        }
+    }
+}
+
+/// Starts a background thread that periodically pushes `stats` to a StatsD agent.
+pub fn spawn(cfg: StatsdConfig, stats: Arc<Stats>) -> io::Result<()> {
+    let sock = UdpSocket::bind(("0.0.0.0", 0))?;
+    sock.connect((&*cfg.host, cfg.port))?;
+    info!("StatsD address: {}:{}", cfg.host, cfg.port);
+
+    let emitter = Emitter { sock, cfg, stats };
+    thread::Builder::new()
+        .name("statsd".to_string())
+        .spawn(move || emitter.run())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(dogstatsd: bool, name: &str, tag: Option<&str>, kind: &str) -> String {
+        let cfg = StatsdConfig {
+            dogstatsd,
+            ..StatsdConfig::default()
+        };
+        let mut out = String::new();
+        format_metric(&mut out, &cfg, name, tag, 42, kind);
+        out
+    }
+
+    #[test]
+    fn format_statsd() {
+        assert_eq!(
+            format(false, "servers", None, "g"),
+            "phantasma.servers:42|g"
+        );
+        assert_eq!(
+            format(false, "packets", Some("challenge"), "c"),
+            "phantasma.packets.challenge:42|c"
+        );
+    }
+
+    #[test]
+    fn format_dogstatsd() {
+        assert_eq!(format(true, "servers", None, "g"), "phantasma.servers:42|g");
+        assert_eq!(
+            format(true, "packets", Some("challenge"), "c"),
+            "phantasma.packets:42|c|#type:challenge"
+        );
+    }
+}